@@ -0,0 +1,29 @@
+//! Feeds arbitrary bytes to the PAC parser, which has to reject anything malformed with an
+//! error rather than panicking.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use unpac::pac::{self, ParseOptions};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = pac::parse(data);
+    let _ = pac::parse_index(data);
+
+    for (_, options) in pac::known_layouts() {
+        let _ = pac::parse_view_with(
+            data,
+            &ParseOptions {
+                repair: true,
+                ..options.clone()
+            },
+        );
+        let _ = pac::parse_view_with(
+            data,
+            &ParseOptions {
+                salvage: true,
+                ..options
+            },
+        );
+    }
+});
@@ -0,0 +1,61 @@
+//! Colors for terminal output, only applied when stdout is a terminal that wants them.
+
+use std::fmt::Display;
+use std::io::IsTerminal;
+
+use owo_colors::{OwoColorize, Style};
+
+use crate::Run;
+
+/// Whether output should be colored: `--color` and `--no-color` decide if given, otherwise
+/// stdout has to be a terminal and `NO_COLOR` unset. Server mode is never colored, as its
+/// output is meant for programs.
+pub fn enabled(opt: &Run) -> bool {
+    if opt.server || opt.no_color {
+        return false;
+    }
+
+    if opt.color {
+        return true;
+    }
+
+    let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+
+    !no_color && std::io::stdout().is_terminal()
+}
+
+pub fn paint(opt: &Run, text: impl Display, style: Style) -> String {
+    if enabled(opt) {
+        text.style(style).to_string()
+    } else {
+        text.to_string()
+    }
+}
+
+pub fn warning(opt: &Run, text: impl Display) -> String {
+    paint(opt, text, Style::new().yellow())
+}
+
+pub fn error(opt: &Run, text: impl Display) -> String {
+    paint(opt, text, Style::new().red().bold())
+}
+
+pub fn size(opt: &Run, text: impl Display) -> String {
+    paint(opt, text, Style::new().cyan())
+}
+
+pub fn added(opt: &Run, text: impl Display) -> String {
+    paint(opt, text, Style::new().green())
+}
+
+/// Colors a file name by its extension, so entries of the same type stand out together.
+pub fn file_name(opt: &Run, name: &str) -> String {
+    match name.rfind('.') {
+        Some(dot) => format!(
+            "{}{}",
+            &name[..dot],
+            paint(opt, &name[dot..], Style::new().magenta())
+        ),
+        None => name.to_string(),
+    }
+}
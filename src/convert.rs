@@ -0,0 +1,271 @@
+//! Conversions between HIP/HPL image data and `image` buffers.
+
+use std::collections::HashMap;
+
+use anyhow::Result as AResult;
+use arcsys::bbcf::hip::BBCFHipImage;
+use arcsys::{IndexedImage, RGBAColor};
+use image::{DynamicImage, GenericImageView, GrayImage, RgbaImage};
+
+/// Fails if the dimensions stored in the HIP don't match the amount of pixel data.
+pub fn hip_to_image(hip: BBCFHipImage) -> AResult<DynamicImage> {
+    type Gray16Image = image::ImageBuffer<image::Luma<u16>, Vec<u16>>;
+
+    let image = match hip {
+        BBCFHipImage::Indexed {
+            width,
+            height,
+            data,
+        } => DynamicImage::ImageLuma8(indexed_to_luma(data.image, width, height)?),
+        BBCFHipImage::Raw {
+            width,
+            height,
+            data,
+        } => DynamicImage::ImageRgba8(raw_to_rgba(data, width, height)?),
+        BBCFHipImage::Luma {
+            width,
+            height,
+            data,
+        } => {
+            check_pixel_count(data.len(), width, height)?;
+            DynamicImage::ImageLuma16(
+                Gray16Image::from_raw(width, height, data)
+                    .ok_or_else(|| size_mismatch(width, height))?,
+            )
+        }
+    };
+
+    Ok(image)
+}
+
+fn size_mismatch(width: u32, height: u32) -> anyhow::Error {
+    anyhow::anyhow!(
+        "Image data doesn't match its dimensions of {}x{}",
+        width,
+        height
+    )
+}
+
+/// Fails unless there are exactly `width * height` pixels, as `image` would otherwise
+/// accept a buffer with extra data at the end.
+fn check_pixel_count(len: usize, width: u32, height: u32) -> AResult<()> {
+    if (width as usize).checked_mul(height as usize) == Some(len) {
+        Ok(())
+    } else {
+        Err(size_mismatch(width, height))
+    }
+}
+
+pub fn raw_to_rgba(raw: Vec<RGBAColor>, width: u32, height: u32) -> AResult<RgbaImage> {
+    check_pixel_count(raw.len(), width, height)?;
+    let pixels: Vec<u8> = raw.into_iter().flat_map(|c| c.to_rgba_slice()).collect();
+
+    RgbaImage::from_vec(width, height, pixels).ok_or_else(|| size_mismatch(width, height))
+}
+
+pub fn indexed_to_luma(pixels: Vec<u8>, width: u32, height: u32) -> AResult<GrayImage> {
+    check_pixel_count(pixels.len(), width, height)?;
+    GrayImage::from_vec(width, height, pixels).ok_or_else(|| size_mismatch(width, height))
+}
+
+/// Looks every index of an indexed image up in `palette`, for a PNG that shows the actual
+/// colors instead of the raw indices.
+pub fn indexed_to_rgba(
+    indices: &[u8],
+    palette: &[RGBAColor],
+    width: u32,
+    height: u32,
+) -> AResult<RgbaImage> {
+    let mut pixels = Vec::with_capacity(indices.len() * 4);
+    for &index in indices {
+        let color = palette.get(index as usize).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Index {} is outside the palette of {} colors",
+                index,
+                palette.len()
+            )
+        })?;
+        pixels.extend_from_slice(&rgba(color));
+    }
+
+    RgbaImage::from_vec(width, height, pixels).ok_or_else(|| size_mismatch(width, height))
+}
+
+/// Turns an image written by `indexed_to_rgba` back into indices into `palette`.
+///
+/// Pixels keep their index from `original` while it still has their color, so unedited
+/// pixels survive even when the palette repeats a color.
+pub fn rgba_to_indexed(
+    image: &RgbaImage,
+    palette: &[RGBAColor],
+    original: &[u8],
+) -> AResult<Vec<u8>> {
+    let mut lookup = HashMap::new();
+    for (index, color) in palette.iter().enumerate().take(256) {
+        lookup.entry(rgba(color)).or_insert(index as u8);
+    }
+
+    image
+        .enumerate_pixels()
+        .zip(0..)
+        .map(|((x, y, pixel), i)| match original.get(i) {
+            Some(&index) if palette.get(index as usize).map(rgba) == Some(pixel.0) => Ok(index),
+            _ => lookup.get(&pixel.0).copied().ok_or_else(|| {
+                anyhow::anyhow!(
+                    "Pixel at {}x{} has color {:?}, which isn't in the palette",
+                    x,
+                    y,
+                    pixel.0
+                )
+            }),
+        })
+        .collect()
+}
+
+fn rgba(color: &RGBAColor) -> [u8; 4] {
+    [color.red, color.green, color.blue, color.alpha]
+}
+
+/// The pixels of `image` as colors, row by row.
+pub fn image_to_colors(image: &DynamicImage) -> Vec<RGBAColor> {
+    image
+        .pixels()
+        .map(|(_, _, c)| {
+            let [red, green, blue, alpha] = c.0;
+            RGBAColor {
+                red,
+                green,
+                blue,
+                alpha,
+            }
+        })
+        .collect()
+}
+
+/// Cuts `palette` to `len` colors, or pads it with its last color.
+pub fn resize_palette(palette: &mut Vec<RGBAColor>, len: usize) {
+    let fill = palette.last().cloned().unwrap_or(RGBAColor {
+        red: 0,
+        green: 0,
+        blue: 0,
+        alpha: 0,
+    });
+    palette.resize(len, fill);
+}
+
+/// A single row of pixels with every color of `palette`. Fails for an empty palette, which
+/// would make an image PNG can't store.
+pub fn palette_to_image(palette: &[RGBAColor]) -> AResult<DynamicImage> {
+    if palette.is_empty() {
+        return Err(anyhow::anyhow!("Palette has no colors"));
+    }
+
+    let width = palette.len() as u32;
+    let pixels: Vec<u8> = palette.iter().flat_map(|c| c.to_rgba_slice()).collect();
+
+    RgbaImage::from_vec(width, 1, pixels)
+        .map(DynamicImage::ImageRgba8)
+        .ok_or_else(|| size_mismatch(width, 1))
+}
+
+/// Encodes an indexed image as a PNG of color type 3, with `palette` in its PLTE chunk and
+/// the alpha of its colors in tRNS.
+pub fn indexed_to_png(
+    indices: &[u8],
+    palette: &[RGBAColor],
+    width: u32,
+    height: u32,
+) -> AResult<Vec<u8>> {
+    if palette.len() > 256 {
+        return Err(anyhow::anyhow!(
+            "Palette of {} colors doesn't fit in a PNG, which holds at most 256",
+            palette.len()
+        ));
+    }
+    if indices.len() != width as usize * height as usize {
+        return Err(size_mismatch(width, height));
+    }
+
+    let plte = palette
+        .iter()
+        .flat_map(|c| vec![c.red, c.green, c.blue])
+        .collect();
+    let trns = palette.iter().map(|c| c.alpha).collect();
+
+    let mut bytes = Vec::new();
+    let mut encoder = png::Encoder::new(&mut bytes, width, height);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(plte);
+    encoder.set_trns(trns);
+
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(indices)?;
+    drop(writer);
+
+    Ok(bytes)
+}
+
+/// Reads a PNG written by `indexed_to_png` back into its dimensions, indices and palette.
+/// Colors missing from tRNS are opaque.
+pub fn png_to_indexed(bytes: &[u8]) -> AResult<(u32, u32, IndexedImage)> {
+    let mut decoder = png::Decoder::new(bytes);
+    decoder.set_transformations(png::Transformations::IDENTITY);
+
+    let (info, mut reader) = decoder.read_info()?;
+    if info.color_type != png::ColorType::Indexed || info.bit_depth != png::BitDepth::Eight {
+        return Err(anyhow::anyhow!(
+            "Expected an 8-bit indexed PNG, got {:?} at {:?}",
+            info.color_type,
+            info.bit_depth
+        ));
+    }
+
+    let mut image = vec![0; info.buffer_size()];
+    reader.next_frame(&mut image)?;
+
+    let png_info = reader.info();
+    let plte = png_info
+        .palette
+        .as_deref()
+        .ok_or_else(|| anyhow::anyhow!("Indexed PNG has no PLTE chunk"))?;
+    let trns = png_info.trns.as_deref().unwrap_or(&[]);
+
+    let palette = plte
+        .chunks_exact(3)
+        .enumerate()
+        .map(|(index, rgb)| RGBAColor {
+            red: rgb[0],
+            green: rgb[1],
+            blue: rgb[2],
+            alpha: trns.get(index).copied().unwrap_or(0xFF),
+        })
+        .collect();
+
+    Ok((info.width, info.height, IndexedImage { palette, image }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn black() -> RGBAColor {
+        RGBAColor {
+            red: 0,
+            green: 0,
+            blue: 0,
+            alpha: 0xFF,
+        }
+    }
+
+    #[test]
+    fn rejects_mismatched_dimensions() {
+        assert!(raw_to_rgba(vec![black(); 6], 2, 3).is_ok());
+        assert!(raw_to_rgba(vec![black(); 5], 2, 3).is_err());
+        assert!(raw_to_rgba(vec![black(); 7], 2, 3).is_err());
+
+        assert!(indexed_to_luma(vec![0; 6], 2, 3).is_ok());
+        assert!(indexed_to_luma(vec![0; 5], 2, 3).is_err());
+        assert!(indexed_to_luma(vec![0; 7], 2, 3).is_err());
+    }
+}
@@ -0,0 +1,57 @@
+use std::path::Path;
+
+use anyhow::Result as AResult;
+use unpac::pac::{self, PacView};
+use unpac::plain_pac_bytes;
+
+use crate::read_file;
+
+/// Parses the PAC at `path` with every known layout and prints how plausible the entries
+/// each of them produces look, to help identify the variant of an unknown archive.
+pub fn print_variants(path: &Path) -> AResult<()> {
+    let file_buf = read_file(path)?;
+    let plain = plain_pac_bytes(&file_buf)?;
+
+    let mut report = format!("{}\n", path.display());
+    for (name, options) in pac::known_layouts() {
+        let summary = match pac::parse_view_with(&plain, &options) {
+            Ok(view) => summarize(&view),
+            Err(e) => format!("fails: {}", e),
+        };
+
+        report.push_str(&format!("    {:<32} {}\n", name, summary));
+    }
+
+    print!("{}", report);
+
+    Ok(())
+}
+
+fn summarize(view: &PacView) -> String {
+    let entries = &view.meta.entries;
+
+    let monotonic = entries.windows(2).all(|w| w[0].offset <= w[1].offset);
+    let printable = entries
+        .iter()
+        .filter(|e| !e.name.is_empty() && !e.name.chars().any(char::is_control))
+        .count();
+
+    let confidence = match (monotonic, printable == entries.len()) {
+        (true, true) => "high",
+        (true, false) | (false, true) => "medium",
+        (false, false) => "low",
+    };
+
+    format!(
+        "{} confidence: {} entries, offsets {}, {}/{} names printable",
+        confidence,
+        entries.len(),
+        if monotonic {
+            "in order"
+        } else {
+            "out of order"
+        },
+        printable,
+        entries.len()
+    )
+}
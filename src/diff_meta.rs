@@ -0,0 +1,148 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use anyhow::{Context, Result as AResult};
+
+use crate::disk::Disk;
+use crate::{color, read_meta, shard, sidecar, MetaKind, Run};
+use unpac::pac::{PacMeta, PacMetaEntry};
+
+/// Prints how the metadata of the extraction folder `new` differs from that of `old`:
+/// header fields, added and removed entries and entries whose id or size changed.
+pub fn print_diff(old: &Path, new: &Path, opt: &Run) -> AResult<()> {
+    let fs = Disk(opt);
+    let old_meta = load(old, &fs).with_context(|| format!("Reading meta of {}", old.display()))?;
+    let new_meta = load(new, &fs).with_context(|| format!("Reading meta of {}", new.display()))?;
+
+    println!("--- {}", old.display());
+    println!("+++ {}", new.display());
+
+    let differences = match (old_meta, new_meta) {
+        (MetaKind::Pac(old), MetaKind::Pac(new)) => diff_pac(&old, &new, opt),
+        (old, new) => {
+            let old = serde_json::to_value(&old)?;
+            let new = serde_json::to_value(&new)?;
+
+            if old == new {
+                0
+            } else {
+                println!("metadata differs");
+                1
+            }
+        }
+    };
+
+    println!("{} differences", differences);
+
+    Ok(())
+}
+
+fn load(folder: &Path, fs: &Disk) -> AResult<MetaKind> {
+    let mut meta = read_meta(folder, fs)?;
+
+    if let MetaKind::Pac(pac) = &mut meta {
+        if pac.split_meta {
+            pac.entries = sidecar::read_sidecars(folder, fs).context("Reading sidecars")?;
+        }
+
+        if let Some(shards) = pac.shards {
+            pac.entries = shard::read_fragments(folder, shards, fs).context("Reading shards")?;
+        }
+    }
+
+    Ok(meta)
+}
+
+fn diff_pac(old: &PacMeta, new: &PacMeta, opt: &Run) -> usize {
+    let mut differences = 0;
+
+    let mut header = |field: &str, old: String, new: String| {
+        if old != new {
+            println!(
+                "{}",
+                color::warning(opt, format!("header {}: {} -> {}", field, old, new))
+            );
+            differences += 1;
+        }
+    };
+
+    header("unknown", old.unknown.to_string(), new.unknown.to_string());
+    header(
+        "string_size",
+        format!("{:#x}", old.string_size),
+        format!("{:#x}", new.string_size),
+    );
+    header(
+        "data_start",
+        format!("{:#x}", old.data_start),
+        format!("{:#x}", new.data_start),
+    );
+    header(
+        "data_align",
+        format!("{:?}", old.data_align),
+        format!("{:?}", new.data_align),
+    );
+    header(
+        "name_terminator",
+        format!("{:?}", old.name_terminator),
+        format!("{:?}", new.name_terminator),
+    );
+    header(
+        "entry_flags",
+        old.entry_flags.to_string(),
+        new.entry_flags.to_string(),
+    );
+
+    let old_entries = by_name(&old.entries);
+    let new_entries = by_name(&new.entries);
+
+    for (name, entry) in &old_entries {
+        match new_entries.get(name) {
+            None => {
+                println!(
+                    "{}",
+                    color::error(opt, format!("- {} (id {})", name, entry.file_id))
+                );
+                differences += 1;
+            }
+            Some(new) => {
+                if entry.file_id != new.file_id {
+                    println!(
+                        "{}",
+                        color::warning(
+                            opt,
+                            format!("~ {} id {} -> {}", name, entry.file_id, new.file_id)
+                        )
+                    );
+                    differences += 1;
+                }
+                if entry.size != new.size {
+                    println!(
+                        "{}",
+                        color::warning(
+                            opt,
+                            format!("~ {} size {} -> {}", name, entry.size, new.size)
+                        )
+                    );
+                    differences += 1;
+                }
+            }
+        }
+    }
+
+    for (name, entry) in &new_entries {
+        if !old_entries.contains_key(name) {
+            println!(
+                "{}",
+                color::added(opt, format!("+ {} (id {})", name, entry.file_id))
+            );
+            differences += 1;
+        }
+    }
+
+    differences
+}
+
+fn by_name(entries: &[PacMetaEntry]) -> BTreeMap<&str, &PacMetaEntry> {
+    entries.iter().map(|e| (e.name.as_str(), e)).collect()
+}
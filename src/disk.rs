@@ -0,0 +1,47 @@
+//! The filesystem the command line tool extracts to and repacks from.
+
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+
+use unpac::vfs::{self, FileSink, FileSource};
+
+use crate::{create_output, read_file, Run};
+
+/// The real filesystem, writing files with the permissions requested in `opt`.
+pub struct Disk<'a>(pub &'a Run);
+
+impl FileSink for Disk<'_> {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        create_output(path, self.0)?.write_all(contents)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn available_space(&self, path: &Path) -> io::Result<Option<u64>> {
+        fs2::available_space(path).map(Some)
+    }
+}
+
+impl FileSource for Disk<'_> {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        read_file(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn list_files(&self, folder: &Path) -> io::Result<Vec<PathBuf>> {
+        vfs::list_files(folder)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+}
@@ -0,0 +1,1685 @@
+//! Extraction folders as written by the command line tool: the entries or images of a file
+//! next to a meta.json describing everything needed to rebuild it.
+//!
+//! `extract_pac`, `extract_hip`, `extract_hpl` and `repack_dir` produce and read the default
+//! layout on disk. Their `_with` forms take the options the command line tool exposes as
+//! flags, a `FileSystem` to work against and a `Report` for the problems they work around.
+
+use std::collections::{HashMap, HashSet};
+use std::io::prelude::*;
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use anyhow::{Context, Result as AResult};
+use arcsys::bbcf::hip::{BBCFHip, BBCFHipImage};
+use arcsys::bbcf::hpl::BBCFHpl;
+use arcsys::bbcf::pac::BBCFPac;
+use arcsys::{IndexedImage, RGBAColor};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use image::{DynamicImage, GenericImageView};
+#[cfg(feature = "rayon")]
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::convert::{
+    hip_to_image, image_to_colors, indexed_to_png, indexed_to_rgba, palette_to_image,
+    png_to_indexed, raw_to_rgba, resize_palette, rgba_to_indexed,
+};
+use crate::format::FileKind;
+use crate::pac::{
+    self, NamedFile, NestedPac, PacError, PacMeta, PacMetaEntry, PacView, ParseOptions,
+    ProgressEvent, Target,
+};
+use crate::text::{self, TextRule};
+use crate::vfs::{FileSink, FileSource, FileSystem, LocalFs};
+use crate::{compress_pac, plain_pac_bytes, shard, sidecar};
+
+pub const META_FILENAME: &str = "meta.json";
+pub const COMPRESSED_META_FILENAME: &str = "meta.json.gz";
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Version of the meta.json format this build writes and the newest it reads. Bump it when
+/// a change to `MetaKind` would make older builds misread new metas.
+pub const META_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct VersionedMeta<'a> {
+    version: u32,
+    meta: &'a MetaKind,
+}
+
+#[derive(Deserialize)]
+struct MetaVersion {
+    version: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct Versioned {
+    meta: MetaKind,
+}
+
+/// The meta.json of a PAC as written before the in-tree parser, which stored the `arcsys`
+/// archive itself. HIP and HPL metas from then still read as a `MetaKind`.
+#[derive(Deserialize)]
+enum LegacyMetaKind {
+    Pac(BBCFPac),
+}
+
+#[derive(Serialize, Deserialize)]
+pub enum MetaKind {
+    Pac(PacMeta),
+    Hip(HipMeta),
+    Hpl(HplMeta),
+}
+
+impl MetaKind {
+    /// The kind of file this is the meta of.
+    pub fn file_kind(&self) -> FileKind {
+        match self {
+            MetaKind::Pac(_) => FileKind::Pac,
+            MetaKind::Hip(_) => FileKind::Hip,
+            MetaKind::Hpl(_) => FileKind::Hpl,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HipMeta {
+    #[serde(flatten)]
+    pub hip: BBCFHip,
+    /// Whether image.png of an indexed image shows colors rather than palette indices
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub colored: bool,
+    /// Whether image.png of an indexed image is an indexed PNG carrying the palette in its
+    /// PLTE chunk, with no palette.png next to it
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub indexed_png: bool,
+    /// Whether only the meta was extracted, with no image to repack from and the pixel data
+    /// left out of `hip`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HplMeta {
+    #[serde(flatten)]
+    pub hpl: BBCFHpl,
+    /// Number of colors in the original palette, restored on repack whatever the width of
+    /// palette.png
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub palette_len: Option<usize>,
+    /// Number of palettes the colors were split into, saved as palette_0.png, palette_1.png,
+    /// ... instead of a single palette.png
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub palette_count: Option<usize>,
+    /// Whether only the meta was extracted, with no palette images to repack from
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+}
+
+/// Colors in one palette of an HPL. Files with several palettes, like alternate color
+/// schemes, store them back to back.
+pub const HPL_PALETTE_COLORS: usize = 256;
+
+/// Number of palettes an HPL palette of `len` colors holds, if it's more than one.
+pub fn hpl_palette_count(len: usize) -> Option<usize> {
+    Some(len / HPL_PALETTE_COLORS).filter(|&count| count > 1 && len % HPL_PALETTE_COLORS == 0)
+}
+
+/// Names of the images the palettes of an HPL split into `palette_count` palettes are saved
+/// as, in order.
+pub fn hpl_palette_files(palette_count: Option<usize>) -> Vec<String> {
+    match palette_count {
+        Some(count) => (0..count)
+            .map(|index| format!("palette_{}.png", index))
+            .collect(),
+        None => vec!["palette.png".to_string()],
+    }
+}
+
+/// Serializes `meta` into the contents of a meta.json, tagged with `META_VERSION`.
+pub fn meta_to_json(meta: &MetaKind) -> serde_json::Result<Vec<u8>> {
+    serde_json::to_vec(&VersionedMeta {
+        version: META_VERSION,
+        meta,
+    })
+}
+
+/// Reads the contents of a meta.json, refusing versions newer than this build knows.
+/// Metas written before versioning are a bare `MetaKind` and still read as is, as are
+/// the PAC metas of 1.0.3 and earlier.
+pub fn meta_from_json(json: &[u8]) -> AResult<MetaKind> {
+    match serde_json::from_slice::<MetaVersion>(json) {
+        Ok(MetaVersion {
+            version: Some(version),
+        }) => {
+            if version > META_VERSION {
+                return Err(anyhow::anyhow!(
+                    "meta.json version {} is not supported by this build (max {})",
+                    version,
+                    META_VERSION
+                ));
+            }
+
+            Ok(serde_json::from_slice::<Versioned>(json)?.meta)
+        }
+        _ => match serde_json::from_slice::<MetaKind>(json) {
+            Ok(meta) => Ok(meta),
+            Err(e) => match serde_json::from_slice::<LegacyMetaKind>(json) {
+                Ok(LegacyMetaKind::Pac(pac)) => Ok(MetaKind::Pac(legacy_pac_meta(pac)?)),
+                Err(_) => Err(e.into()),
+            },
+        },
+    }
+}
+
+/// Turns the `arcsys` archive a legacy meta.json stored into the meta of the same archive,
+/// by writing it out and reading the entry table back.
+fn legacy_pac_meta(pac: BBCFPac) -> AResult<PacMeta> {
+    let plain = pac.to_bytes();
+
+    Ok(pac::parse_view(&plain)
+        .context("Reading legacy PAC meta")?
+        .meta)
+}
+
+/// The meta file in `folder`, compressed or not, if there is one.
+pub fn find_meta(folder: &Path, fs: &impl FileSource) -> Option<PathBuf> {
+    [META_FILENAME, COMPRESSED_META_FILENAME]
+        .iter()
+        .map(|name| folder.join(name))
+        .find(|path| fs.is_file(path))
+}
+
+/// Reads the meta of `folder`, decompressing it if it starts with the gzip magic.
+pub fn read_meta(folder: &Path, fs: &impl FileSource) -> AResult<MetaKind> {
+    let meta_path = find_meta(folder, fs)
+        .ok_or_else(|| anyhow::anyhow!("No meta.json found in {}", folder.display()))?;
+    let meta_bytes = fs
+        .read(&meta_path)
+        .with_context(|| format!("Opening {}", meta_path.display()))?;
+
+    if meta_bytes.starts_with(GZIP_MAGIC) {
+        let mut json = Vec::new();
+        GzDecoder::new(meta_bytes.as_slice())
+            .read_to_end(&mut json)
+            .context("Decompressing meta.json.gz")?;
+
+        meta_from_json(&json).context("Parsing meta.json.gz")
+    } else {
+        meta_from_json(&meta_bytes).context("Parsing meta.json")
+    }
+}
+
+/// Writes `meta` into `folder`, as meta.json.gz if `compress` is set, removing a meta left
+/// there in the other form.
+pub fn write_meta(
+    folder: &Path,
+    meta: &MetaKind,
+    compress: bool,
+    fs: &impl FileSystem,
+) -> AResult<()> {
+    let (name, stale) = if compress {
+        (COMPRESSED_META_FILENAME, META_FILENAME)
+    } else {
+        (META_FILENAME, COMPRESSED_META_FILENAME)
+    };
+
+    let json = meta_to_json(meta).with_context(|| format!("Writing {}", name))?;
+
+    let meta_bytes = if compress {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder
+            .write_all(&json)
+            .and_then(|()| encoder.finish())
+            .with_context(|| format!("Writing {}", name))?
+    } else {
+        json
+    };
+
+    fs.write(&folder.join(name), &meta_bytes)
+        .with_context(|| format!("Creating {}", name))?;
+
+    // a leftover meta in the other form would shadow or contradict the new one
+    let stale = folder.join(stale);
+    if fs.is_file(&stale) {
+        fs.remove_file(&stale)
+            .with_context(|| format!("Removing stale {}", stale.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Folder a nested PAC extracted to `entry_path` lives in, its path without the extension.
+pub fn nested_folder(entry_path: &Path) -> PathBuf {
+    entry_path.with_extension("")
+}
+
+/// How files are extracted. The defaults write every entry and image in the plain layout.
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    pub parse: ParseOptions,
+    /// Only extract PAC entries that are HIP images
+    pub only_images: bool,
+    /// Only extract PAC entries that are not HIP images
+    pub only_data: bool,
+    /// Only write the meta, skipping entry files and images
+    pub meta_only: bool,
+    /// Write the meta gzip-compressed, as meta.json.gz
+    pub compress_meta: bool,
+    /// Write a `<entry>.meta.json` next to each PAC entry instead of listing them all in
+    /// meta.json
+    pub split_meta: bool,
+    /// Spread PAC entries over this many shard folders balanced by total size
+    pub shards: Option<NonZeroU32>,
+    /// Content-addressed store PAC entries are written into, named by their SHA-256
+    pub cas: Option<PathBuf>,
+    /// Entries matching one of these are extracted as UTF-8 text with LF line endings
+    pub text_entries: Vec<TextRule>,
+    /// Extract PAC entries that are PACs themselves into a folder of their own
+    pub recursive: bool,
+    /// How many levels of PACs inside PACs `recursive` extracts
+    pub max_depth: usize,
+    /// Re-read every extracted PAC entry and check it matches what was extracted
+    pub verify_write: bool,
+    /// Bytes that must still be free on the target disk after extracting a PAC, or `None`
+    /// to not check
+    pub min_free_space: Option<u64>,
+    /// Write indexed HIP images as a grayscale map of their palette indices
+    pub raw_indices: bool,
+    /// Write indexed HIP images as a single indexed-color image.png carrying the palette
+    pub indexed_png: bool,
+    /// Palette index of indexed HIP images that is exported fully transparent
+    pub transparent_index: Option<u8>,
+}
+
+/// How extraction folders are repacked. The defaults rebuild the file as its meta describes.
+#[derive(Debug, Clone, Default)]
+pub struct RepackOptions {
+    /// Header values a game expects, applied to PACs before `data_align`
+    pub target: Option<Target>,
+    /// Start the data region of PACs on a multiple of this many bytes
+    pub data_align: Option<u32>,
+    /// Store every PAC entry with identical contents only once
+    pub dedup_data: bool,
+    /// Leave PACs uncompressed, even if the original archive was compressed
+    pub no_compress: bool,
+    /// Skip parsing each rebuilt PAC back to check its entries
+    pub no_self_check: bool,
+    /// Give PAC entries sequential file_ids in table order instead of the ids in the meta
+    pub renumber_ids: bool,
+    /// Repack PAC folders that only hold some of their entries, leaving out the rest
+    pub allow_partial: bool,
+    /// Palette index of indexed HIP images whose original alpha is restored
+    pub transparent_index: Option<u8>,
+}
+
+/// Where extraction and repack report the problems they work around.
+pub trait Report: Sync {
+    /// A recoverable problem. Returning an error stops whatever is being extracted or
+    /// repacked instead.
+    fn warn(&self, message: String) -> AResult<()>;
+
+    /// Called before the entries of the PAC at `path`, nested ones included, are extracted
+    /// into `folder`.
+    fn extracting_pac(&self, _path: &Path, _meta: &PacMeta, _folder: &Path) {}
+
+    /// Called for each PAC entry as it is extracted or read back for repack. Entries of
+    /// nested PACs count against their own archive.
+    fn progress(&self, _event: ProgressEvent) {}
+
+    /// Checked between PAC entries, stopping extraction or repack with
+    /// `PacError::Cancelled` once it returns true.
+    fn cancelled(&self) -> bool {
+        false
+    }
+}
+
+/// Lets every problem pass without a word, as the plain `extract_*` and `repack_dir`
+/// functions do.
+pub struct Silent;
+
+impl Report for Silent {
+    fn warn(&self, _message: String) -> AResult<()> {
+        Ok(())
+    }
+}
+
+/// Passes everything on to `report`, along with progress to `progress` and cancellation
+/// through `cancel`, for the `_with_progress` functions.
+struct WithProgress<'a, R> {
+    report: &'a R,
+    progress: &'a (dyn Fn(ProgressEvent) + Sync),
+    cancel: &'a AtomicBool,
+}
+
+impl<R: Report> Report for WithProgress<'_, R> {
+    fn warn(&self, message: String) -> AResult<()> {
+        self.report.warn(message)
+    }
+
+    fn extracting_pac(&self, path: &Path, meta: &PacMeta, folder: &Path) {
+        self.report.extracting_pac(path, meta, folder)
+    }
+
+    fn progress(&self, event: ProgressEvent) {
+        (self.progress)(event)
+    }
+
+    fn cancelled(&self) -> bool {
+        self.cancel.load(Ordering::Relaxed)
+    }
+}
+
+fn check_cancelled(report: &impl Report) -> AResult<()> {
+    if report.cancelled() {
+        Err(PacError::Cancelled.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Extracts the PAC in `input` into `out`, every entry as a file of its own.
+pub fn extract_pac(input: &[u8], out: &Path) -> AResult<()> {
+    extract_pac_with(
+        out,
+        input,
+        out,
+        &ExtractOptions::default(),
+        &LocalFs,
+        &Silent,
+    )
+}
+
+/// Extracts the HIP in `input` into `out` as image.png, plus palette.png for indexed
+/// images, which are written in color.
+pub fn extract_hip(input: &[u8], out: &Path) -> AResult<()> {
+    extract_hip_with(input, out, &ExtractOptions::default(), &LocalFs)
+}
+
+/// Extracts the HPL in `input` into `out` as palette.png, or as palette_0.png,
+/// palette_1.png, ... if it holds several palettes.
+pub fn extract_hpl(input: &[u8], out: &Path) -> AResult<()> {
+    extract_hpl_with(input, out, &ExtractOptions::default(), &LocalFs, &Silent)
+}
+
+/// Extracts the PAC in `input`, read from `path`, into `out`.
+pub fn extract_pac_with(
+    path: &Path,
+    input: &[u8],
+    out: &Path,
+    options: &ExtractOptions,
+    fs: &impl FileSystem,
+    report: &impl Report,
+) -> AResult<()> {
+    extract_nested_pac(path, input, out, options, fs, report, 0)
+}
+
+/// Extracts like `extract_pac_with`, calling `progress` after each entry and stopping with
+/// `PacError::Cancelled` as soon as `cancel` is set.
+///
+/// meta.json is only written once every entry is, so a cancelled extraction never leaves a
+/// folder that repacks into a shorter PAC.
+#[allow(clippy::too_many_arguments)]
+pub fn extract_pac_with_progress(
+    path: &Path,
+    input: &[u8],
+    out: &Path,
+    options: &ExtractOptions,
+    fs: &impl FileSystem,
+    report: &impl Report,
+    progress: &(dyn Fn(ProgressEvent) + Sync),
+    cancel: &AtomicBool,
+) -> AResult<()> {
+    let report = WithProgress {
+        report,
+        progress,
+        cancel,
+    };
+    extract_nested_pac(path, input, out, options, fs, &report, 0)?;
+    progress(ProgressEvent::Finished);
+
+    Ok(())
+}
+
+/// Parses the PAC `plain`, decompressed from `input`, reporting any entries `options` had
+/// to repair or leave out.
+pub fn parse_pac<'a>(
+    path: &Path,
+    input: &[u8],
+    plain: &'a [u8],
+    options: &ParseOptions,
+    report: &impl Report,
+) -> AResult<PacView<'a>> {
+    let mut pac = pac::parse_view_with(plain, options).context("Parsing PAC")?;
+    pac.meta.uncompressed = input.starts_with(pac::HEADER_MAGIC);
+
+    if let Some(broken) = pac.repaired_from {
+        report.warn(format!(
+            "{} has a broken string_size of {:#x}, read it as {:#x} instead",
+            path.display(),
+            broken,
+            pac.meta.string_size
+        ))?;
+    }
+
+    if let Some(total) = pac.salvaged_from {
+        report.warn(format!(
+            "{} is truncated, recovered {} of {} entries",
+            path.display(),
+            pac.meta.entries.len(),
+            total
+        ))?;
+    }
+
+    Ok(pac)
+}
+
+/// Extracts the PAC in `input` into `out`. `depth` counts the PACs it is nested in.
+fn extract_nested_pac(
+    path: &Path,
+    input: &[u8],
+    out: &Path,
+    options: &ExtractOptions,
+    fs: &impl FileSystem,
+    report: &impl Report,
+    depth: usize,
+) -> AResult<()> {
+    let plain = plain_pac_bytes(input).context("Decompressing PAC")?;
+    let mut pac = parse_pac(path, input, &plain, &options.parse, report)?;
+
+    report.extracting_pac(path, &pac.meta, out);
+
+    fs.create_dir_all(out)?;
+
+    if let (Some(min_free_space), false) = (options.min_free_space, options.meta_only) {
+        let total_size: u64 = pac.meta.entries.iter().map(|e| e.size).sum();
+        ensure_free_space(out, total_size, min_free_space, fs)?;
+    }
+
+    if let Some(cas) = &options.cas {
+        fs.create_dir_all(cas)?;
+        pac.meta.cas = Some(fs.canonicalize(cas)?);
+    }
+
+    pac::assign_file_names(&mut pac.meta.entries);
+
+    pac.meta.partial = options.meta_only
+        || pac
+            .contents
+            .iter()
+            .any(|contents| !is_selected(contents, options));
+
+    if let Some(shards) = options.shards {
+        shard::assign_shards(&mut pac.meta.entries, shards.get());
+        shard::write_fragments(&pac.meta.entries, shards.get(), out, fs)?;
+    }
+
+    if !options.meta_only {
+        write_pac_entries(&mut pac, out, options, fs, report, depth)?;
+        check_cancelled(report)?;
+
+        if options.verify_write {
+            verify_pac_entries(&pac, out, options, fs).context("Verifying written entries")?;
+        }
+    }
+
+    if options.split_meta {
+        sidecar::write_sidecars(&pac.meta.entries, out, fs)?;
+        pac.meta.entries = Vec::new();
+        pac.meta.split_meta = true;
+    }
+
+    if let Some(shards) = options.shards {
+        pac.meta.entries = Vec::new();
+        pac.meta.shards = Some(shards.get());
+    }
+
+    write_meta(out, &MetaKind::Pac(pac.meta), options.compress_meta, fs)
+}
+
+/// Refuses to extract `needed` bytes into `folder` unless the disk keeps at least
+/// `min_free_space` bytes free afterwards, so a full disk doesn't leave half an extraction.
+pub fn ensure_free_space(
+    folder: &Path,
+    needed: u64,
+    min_free_space: u64,
+    fs: &impl FileSink,
+) -> AResult<()> {
+    let available = match fs
+        .available_space(folder)
+        .with_context(|| format!("Checking free space in {}", folder.display()))?
+    {
+        Some(available) => available,
+        None => return Ok(()),
+    };
+
+    if available < needed.saturating_add(min_free_space) {
+        return Err(anyhow::anyhow!(
+            "Extracting needs {} but only {} is free in {} (keeping {} free), pass --force to extract anyway",
+            format_size(needed),
+            format_size(available),
+            folder.display(),
+            format_size(min_free_space)
+        ));
+    }
+
+    Ok(())
+}
+
+/// Formats `bytes` in the largest binary unit that keeps it above 1, e.g. `3.2 GiB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+fn write_pac_entries(
+    pac: &mut PacView,
+    storage_folder: &Path,
+    options: &ExtractOptions,
+    fs: &impl FileSystem,
+    report: &impl Report,
+    depth: usize,
+) -> AResult<()> {
+    let cas = pac.meta.cas.as_deref();
+
+    // with rayon this runs on the same pool as the loop over input files, so the entries of
+    // one archive take up threads left idle by the others instead of adding more
+    #[cfg(feature = "rayon")]
+    let entries = pac.meta.entries.par_iter_mut();
+    #[cfg(not(feature = "rayon"))]
+    let entries = pac.meta.entries.iter_mut();
+
+    let total = pac.contents.len();
+
+    entries
+        .zip(&pac.contents)
+        .enumerate()
+        .try_for_each(|(index, (entry, contents))| {
+            check_cancelled(report)?;
+            write_pac_entry(
+                entry,
+                contents,
+                cas,
+                storage_folder,
+                options,
+                fs,
+                report,
+                depth,
+            )?;
+            report.progress(ProgressEvent::Entry {
+                index,
+                total,
+                name: &entry.name,
+            });
+
+            Ok(())
+        })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn write_pac_entry(
+    entry: &mut PacMetaEntry,
+    contents: &[u8],
+    cas: Option<&Path>,
+    storage_folder: &Path,
+    options: &ExtractOptions,
+    fs: &impl FileSystem,
+    report: &impl Report,
+    depth: usize,
+) -> AResult<()> {
+    if !is_selected(contents, options) {
+        return Ok(());
+    }
+
+    let entry_path = match cas {
+        Some(cas) => {
+            let hash = format!("{:x}", Sha256::digest(contents));
+            let blob_path = cas.join(&hash);
+            entry.hash = Some(hash);
+
+            // identical blobs are already stored, nothing to write. Entries sharing a blob
+            // within one archive may both write it, with the same contents
+            if fs.is_file(&blob_path) {
+                return Ok(());
+            }
+
+            blob_path
+        }
+        None if !pac::is_safe_file_name(entry.file_name()) => {
+            return Err(anyhow::anyhow!(
+                "Entry {} would be written outside of {}",
+                entry.name,
+                storage_folder.display()
+            ))
+        }
+        None => shard::entry_path(storage_folder, entry),
+    };
+
+    if cas.is_none() && options.recursive {
+        if let Some(form) = nested_pac_form(&entry.name, contents) {
+            if depth < options.max_depth {
+                extract_nested_pac(
+                    &entry_path,
+                    contents,
+                    &nested_folder(&entry_path),
+                    options,
+                    fs,
+                    report,
+                    depth + 1,
+                )
+                .with_context(|| format!("Extracting nested PAC {}", entry.name))?;
+                entry.nested = Some(form);
+                return Ok(());
+            }
+
+            report.warn(format!(
+                "{} is nested deeper than --max-depth {}, extracting it as a file",
+                entry.name, options.max_depth
+            ))?;
+        }
+    }
+
+    if cas.is_none() {
+        if let Some((editable, form)) =
+            text::to_editable(&entry.name, contents, &options.text_entries)
+        {
+            entry.text = Some(form);
+            return fs
+                .write(&entry_path, &editable)
+                .with_context(|| format!("Writing entry {}", entry.name));
+        }
+    }
+
+    fs.write(&entry_path, contents)
+        .with_context(|| format!("Writing entry {}", entry.name))
+}
+
+/// How the entry `name` with `contents` is stored if it is a PAC itself: one with a PAC magic,
+/// or a `.pac` whose contents decompress to one.
+fn nested_pac_form(name: &str, contents: &[u8]) -> Option<NestedPac> {
+    if contents.starts_with(pac::HEADER_MAGIC) {
+        Some(NestedPac::Plain)
+    } else if FileKind::sniff(contents) == Some(FileKind::Pac)
+        || (name.to_lowercase().ends_with(".pac") && plain_pac_bytes(contents).is_ok())
+    {
+        Some(NestedPac::Compressed)
+    } else {
+        None
+    }
+}
+
+/// Whether an entry with `contents` passes the `only_images`/`only_data` filters.
+pub fn is_selected(contents: &[u8], options: &ExtractOptions) -> bool {
+    let is_image = FileKind::sniff(contents) == Some(FileKind::Hip);
+
+    !((options.only_images && !is_image) || (options.only_data && is_image))
+}
+
+/// Re-reads every entry written by `write_pac_entries` and compares it against the data it
+/// was extracted from, to catch writes that silently didn't make it to disk.
+fn verify_pac_entries(
+    pac: &PacView,
+    storage_folder: &Path,
+    options: &ExtractOptions,
+    fs: &impl FileSource,
+) -> AResult<()> {
+    let mut mismatched = Vec::new();
+
+    for (entry, contents) in pac.meta.entries.iter().zip(&pac.contents) {
+        if !is_selected(contents, options) {
+            continue;
+        }
+
+        // checked as part of extracting the nested PAC itself
+        if entry.nested.is_some() {
+            continue;
+        }
+
+        let entry_path = match (&pac.meta.cas, &entry.hash) {
+            (Some(cas), Some(hash)) => cas.join(hash),
+            (Some(_), None) => continue,
+            (None, _) => shard::entry_path(storage_folder, entry),
+        };
+
+        let written = fs.read(&entry_path).and_then(|written| match &entry.text {
+            Some(form) => text::restore(&written, form)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string())),
+            None => Ok(written),
+        });
+
+        match written {
+            Ok(written) if written == *contents => {}
+            Ok(written) => mismatched.push(format!(
+                "{} ({} bytes on disk, expected {})",
+                entry.name,
+                written.len(),
+                contents.len()
+            )),
+            Err(e) => mismatched.push(format!("{} ({})", entry.name, e)),
+        }
+    }
+
+    if !mismatched.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{} entries don't match what was extracted: {}",
+            mismatched.len(),
+            mismatched.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+/// Extracts the HIP in `input` into `out` as image.png, plus palette.png for indexed images
+/// unless they're written as an indexed PNG.
+pub fn extract_hip_with(
+    input: &[u8],
+    out: &Path,
+    options: &ExtractOptions,
+    fs: &impl FileSystem,
+) -> AResult<()> {
+    let mut hip = BBCFHip::parse(input).context("Parsing HIP")?;
+
+    fs.create_dir_all(out)?;
+
+    let indexed = matches!(hip.image, BBCFHipImage::Indexed { .. });
+    let indexed_png = options.indexed_png && indexed;
+    let colored = !options.raw_indices && !indexed_png && indexed;
+
+    if !options.meta_only {
+        let image = match &hip.image {
+            BBCFHipImage::Indexed {
+                width,
+                height,
+                data,
+            } => {
+                let mut palette = data.palette.clone();
+                if let Some(color) = options
+                    .transparent_index
+                    .and_then(|index| palette.get_mut(index as usize))
+                {
+                    color.alpha = 0;
+                }
+
+                if indexed_png {
+                    indexed_to_png(&data.image, &palette, *width, *height)
+                        .context("Encoding HIP image")?
+                } else {
+                    let palette_image =
+                        palette_to_image(&palette).context("Decoding HIP palette")?;
+                    save_png(&palette_image, &out.join("palette.png"), fs)
+                        .context("Writing palette.png")?;
+
+                    let image = if colored {
+                        DynamicImage::ImageRgba8(
+                            indexed_to_rgba(&data.image, &palette, *width, *height)
+                                .context("Decoding HIP image")?,
+                        )
+                    } else {
+                        hip_to_image(hip.image.clone()).context("Decoding HIP image")?
+                    };
+                    png_bytes(&image)?
+                }
+            }
+            _ => png_bytes(&hip_to_image(hip.image.clone()).context("Decoding HIP image")?)?,
+        };
+
+        fs.write(&out.join("image.png"), &image)
+            .context("Writing image.png")?;
+    } else {
+        strip_pixels(&mut hip.image);
+    }
+
+    write_meta(
+        out,
+        &MetaKind::Hip(HipMeta {
+            hip,
+            colored,
+            indexed_png,
+            partial: options.meta_only,
+        }),
+        options.compress_meta,
+        fs,
+    )
+}
+
+/// Empties the pixel data of `image`, keeping its dimensions and palette.
+fn strip_pixels(image: &mut BBCFHipImage) {
+    match image {
+        BBCFHipImage::Indexed { data, .. } => data.image.clear(),
+        BBCFHipImage::Raw { data, .. } => data.clear(),
+        BBCFHipImage::Luma { data, .. } => data.clear(),
+    }
+}
+
+/// Extracts the HPL in `input` into `out` as palette.png, or as palette_0.png,
+/// palette_1.png, ... if it holds several palettes.
+pub fn extract_hpl_with(
+    input: &[u8],
+    out: &Path,
+    options: &ExtractOptions,
+    fs: &impl FileSystem,
+    report: &impl Report,
+) -> AResult<()> {
+    let mut hpl = BBCFHpl::parse(input).context("Parsing HPL")?;
+
+    let palette_len = hpl.palette.len();
+    let palette_count = hpl_palette_count(palette_len);
+    let files = hpl_palette_files(palette_count);
+    let colors = palette_len / files.len();
+
+    // the colors go into the palette images rather than meta.json
+    let original_palette = std::mem::take(&mut hpl.palette);
+
+    let hpl = MetaKind::Hpl(HplMeta {
+        hpl,
+        palette_len: Some(palette_len),
+        palette_count,
+        partial: options.meta_only,
+    });
+
+    if !hpl_meta_round_trips(&hpl, original_palette.clone(), input)? {
+        report.warn(
+            "HPL header fields do not survive meta.json unchanged, repacking will not reproduce the original file".to_string(),
+        )?;
+    }
+
+    fs.create_dir_all(out)?;
+
+    if !options.meta_only {
+        for (index, file) in files.iter().enumerate() {
+            let palette = &original_palette[index * colors..(index + 1) * colors];
+            save_png(
+                &DynamicImage::ImageRgba8(raw_to_rgba(palette.to_vec(), colors as u32, 1)?),
+                &out.join(file),
+                fs,
+            )
+            .with_context(|| format!("Writing {}", file))?;
+        }
+    }
+
+    write_meta(out, &hpl, options.compress_meta, fs)
+}
+
+/// Whether rebuilding the HPL from its serialized meta and the untouched palette gives back
+/// exactly `original`, i.e. no header field gets dropped or defaulted on the way.
+fn hpl_meta_round_trips(
+    meta: &MetaKind,
+    palette: Vec<RGBAColor>,
+    original: &[u8],
+) -> AResult<bool> {
+    let serialized = meta_to_json(meta).context("Serializing HPL meta")?;
+
+    match meta_from_json(&serialized).context("Reading back HPL meta")? {
+        MetaKind::Hpl(HplMeta { mut hpl, .. }) => {
+            hpl.palette = palette;
+            Ok(hpl.to_bytes() == original)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Rebuilds the file extracted into `folder`, returning its bytes instead of writing them.
+///
+/// A compressed PAC that can't be compressed again without changing its layout comes back
+/// uncompressed, see [`compress_pac`].
+///
+/// A palette image whose width changed is cut or padded with its last color to the original
+/// palette size. Indexed HIP images saved as a single indexed PNG take their palette from
+/// its PLTE chunk.
+pub fn repack_dir(folder: &Path) -> AResult<Vec<u8>> {
+    repack_dir_with(folder, &RepackOptions::default(), &LocalFs, &Silent)
+}
+
+/// Rebuilds like `repack_dir_with`, calling `progress` for each PAC entry it reads back
+/// and stopping with `PacError::Cancelled` as soon as `cancel` is set. Nothing is returned
+/// on cancellation, so there is never a half-built file to write.
+pub fn repack_dir_with_progress(
+    folder: &Path,
+    options: &RepackOptions,
+    fs: &impl FileSystem,
+    report: &impl Report,
+    progress: &(dyn Fn(ProgressEvent) + Sync),
+    cancel: &AtomicBool,
+) -> AResult<Vec<u8>> {
+    let report = WithProgress {
+        report,
+        progress,
+        cancel,
+    };
+    let bytes = repack_dir_with(folder, options, fs, &report)?;
+    progress(ProgressEvent::Finished);
+
+    Ok(bytes)
+}
+
+/// Rebuilds the file extracted into `folder` from its meta, compressed or not.
+pub fn repack_dir_with(
+    folder: &Path,
+    options: &RepackOptions,
+    fs: &impl FileSystem,
+    report: &impl Report,
+) -> AResult<Vec<u8>> {
+    let meta = read_meta(folder, fs)?;
+
+    repack_meta(folder, meta, options, fs, report)
+}
+
+/// Rebuilds the file extracted into `folder` from `meta`, already read from it.
+pub fn repack_meta(
+    folder: &Path,
+    meta: MetaKind,
+    options: &RepackOptions,
+    fs: &impl FileSystem,
+    report: &impl Report,
+) -> AResult<Vec<u8>> {
+    match meta {
+        MetaKind::Pac(meta) => {
+            let mut meta = prepare_pac_meta(folder, meta, options, fs, report)?;
+            let files = read_pac_entries(folder, &mut meta, options, fs, report)?;
+
+            rebuild_pac(folder, &meta, &files, options, report)
+        }
+        MetaKind::Hpl(HplMeta {
+            mut hpl,
+            palette_len,
+            palette_count,
+            partial,
+        }) => {
+            ensure_complete(folder, partial, options)?;
+
+            let files = hpl_palette_files(palette_count);
+            let colors = palette_len.map(|len| len / files.len());
+
+            let mut palette = Vec::new();
+            for file in &files {
+                let mut part = image_to_colors(
+                    &load_image(&folder.join(file), fs)
+                        .with_context(|| format!("Reading {}", file))?,
+                );
+
+                if let Some(len) = colors {
+                    if part.len() != len {
+                        report.warn(format!(
+                            "{} has {} colors but the original palette had {}, resizing it to match",
+                            file,
+                            part.len(),
+                            len
+                        ))?;
+                        resize_palette(&mut part, len);
+                    }
+                }
+
+                palette.extend(part);
+            }
+
+            hpl.palette = palette;
+
+            Ok(hpl.to_bytes())
+        }
+        MetaKind::Hip(HipMeta {
+            mut hip,
+            colored,
+            indexed_png,
+            partial,
+        }) => {
+            ensure_complete(folder, partial, options)?;
+
+            hip.image = match hip.image {
+                BBCFHipImage::Indexed {
+                    width: _,
+                    height: _,
+                    data: original,
+                } if indexed_png => {
+                    let bytes = fs
+                        .read(&folder.join("image.png"))
+                        .context("Reading image.png")?;
+                    let (width, height, mut data) =
+                        png_to_indexed(&bytes).context("Reading image.png")?;
+
+                    restore_transparent_index(&mut data.palette, &original.palette, options);
+
+                    BBCFHipImage::Indexed {
+                        width,
+                        height,
+                        data,
+                    }
+                }
+                BBCFHipImage::Indexed {
+                    width: _,
+                    height: _,
+                    data: original,
+                } => {
+                    let image =
+                        load_image(&folder.join("image.png"), fs).context("Reading image.png")?;
+                    ensure_8bit(&image, "image.png")?;
+                    let palette = load_image(&folder.join("palette.png"), fs)
+                        .context("Reading palette.png")?;
+
+                    let (width, height) = image.dimensions();
+
+                    let mut palette = image_to_colors(&palette);
+
+                    // matched against the palette as written, before restoring any alpha
+                    let image = if colored {
+                        rgba_to_indexed(&image.to_rgba8(), &palette, &original.image)
+                            .context("Matching image.png to palette.png")?
+                    } else {
+                        image.to_luma8().to_vec()
+                    };
+
+                    restore_transparent_index(&mut palette, &original.palette, options);
+
+                    BBCFHipImage::Indexed {
+                        width,
+                        height,
+                        data: IndexedImage { palette, image },
+                    }
+                }
+                BBCFHipImage::Raw {
+                    width: _,
+                    height: _,
+                    data: _,
+                } => {
+                    let image =
+                        load_image(&folder.join("image.png"), fs).context("Reading image.png")?;
+                    ensure_8bit(&image, "image.png")?;
+
+                    let (width, height) = image.dimensions();
+
+                    let image = image_to_colors(&image);
+
+                    BBCFHipImage::Raw {
+                        width,
+                        height,
+                        data: image,
+                    }
+                }
+                BBCFHipImage::Luma {
+                    width: _,
+                    height: _,
+                    data: _,
+                } => {
+                    let image =
+                        load_image(&folder.join("image.png"), fs).context("Reading image.png")?;
+                    let (width, height) = image.dimensions();
+
+                    BBCFHipImage::Luma {
+                        width,
+                        height,
+                        data: image.to_luma16().to_vec(),
+                    }
+                }
+            };
+
+            Ok(hip.to_bytes())
+        }
+    }
+}
+
+/// Gets the PAC meta read from `folder` ready to be repacked: fills in the entries it split
+/// off, checks them against the folder and applies the header options of `options`.
+pub fn prepare_pac_meta(
+    folder: &Path,
+    mut meta: PacMeta,
+    options: &RepackOptions,
+    fs: &impl FileSource,
+    report: &impl Report,
+) -> AResult<PacMeta> {
+    check_pac_folder(folder, &mut meta, options, fs, report)?;
+    apply_header_overrides(&mut meta, options);
+
+    Ok(meta)
+}
+
+/// Fills in the entries of `meta` that were split off and checks them against `folder`.
+fn check_pac_folder(
+    folder: &Path,
+    meta: &mut PacMeta,
+    options: &RepackOptions,
+    fs: &impl FileSource,
+    report: &impl Report,
+) -> AResult<()> {
+    ensure_complete(folder, meta.partial, options)?;
+    read_entry_list(folder, meta, fs)?;
+    reconcile_pac_folder(folder, meta, options, fs, report)
+}
+
+/// Applies the header options of `options` to `meta`: first the `target` profile, then any
+/// field given explicitly.
+pub fn apply_header_overrides(meta: &mut PacMeta, options: &RepackOptions) {
+    if let Some(target) = &options.target {
+        target.apply(meta);
+    }
+
+    if options.data_align.is_some() {
+        meta.data_align = options.data_align;
+    }
+
+    meta.dedup_data |= options.dedup_data;
+}
+
+/// Fills in the entries of `meta` from the sidecars or shard lists they were split into.
+fn read_entry_list(folder: &Path, meta: &mut PacMeta, fs: &impl FileSource) -> AResult<()> {
+    if meta.split_meta {
+        meta.entries = sidecar::read_sidecars(folder, fs).context("Reading sidecars")?;
+    }
+
+    if let Some(shards) = meta.shards {
+        meta.entries = shard::read_fragments(folder, shards, fs).context("Reading shards")?;
+    }
+
+    Ok(())
+}
+
+/// Warns about every file in the extraction folder `folder` that `meta` doesn't account
+/// for, since files added to the folder are not picked up on repack, and about entries
+/// sharing a file_id.
+///
+/// Folders of CAS or sharded extractions keep their entries elsewhere and aren't checked
+/// for extra files.
+fn reconcile_pac_folder(
+    folder: &Path,
+    meta: &PacMeta,
+    options: &RepackOptions,
+    fs: &impl FileSource,
+    report: &impl Report,
+) -> AResult<()> {
+    if meta.cas.is_none() && meta.shards.is_none() {
+        let mut known: HashSet<String> = [META_FILENAME, COMPRESSED_META_FILENAME]
+            .iter()
+            .map(|name| name.to_string())
+            .collect();
+        for entry in &meta.entries {
+            known.insert(entry.file_name().to_string());
+            if meta.split_meta {
+                known.insert(sidecar::sidecar_name(entry));
+            }
+        }
+
+        let mut untracked: Vec<PathBuf> = fs
+            .list_files(folder)
+            .with_context(|| format!("Listing {}", folder.display()))?
+            .into_iter()
+            .filter(|file| {
+                file.file_name()
+                    .and_then(|name| name.to_str())
+                    .map_or(true, |name| !known.contains(name))
+            })
+            .collect();
+        untracked.sort();
+
+        for file in untracked {
+            report.warn(format!(
+                "{} is not listed in meta.json and won't be added to the PAC",
+                file.display()
+            ))?;
+        }
+    }
+
+    // renumbered ids are unique anyway
+    if options.renumber_ids {
+        return Ok(());
+    }
+
+    let mut ids = HashMap::new();
+    for entry in &meta.entries {
+        if let Some(first) = ids.insert(entry.file_id, &entry.name) {
+            report.warn(format!(
+                "{} and {} both have file_id {}",
+                first, entry.name, entry.file_id
+            ))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Gives the entries of `meta` sequential file_ids in table order with `renumber_ids`,
+/// otherwise leaves the ids read from meta.json as they are.
+pub fn assign_file_ids(meta: &mut PacMeta, options: &RepackOptions) {
+    if options.renumber_ids {
+        for (file_id, entry) in meta.entries.iter_mut().enumerate() {
+            entry.file_id = file_id as u32;
+        }
+    }
+}
+
+/// Reads the contents of every entry of `meta` out of the extraction folder `folder`,
+/// rebuilding nested PACs from their own folders. Entries that can't be read are dropped
+/// from `meta`.
+fn read_pac_entries(
+    folder: &Path,
+    meta: &mut PacMeta,
+    options: &RepackOptions,
+    fs: &impl FileSystem,
+    report: &impl Report,
+) -> AResult<Vec<NamedFile>> {
+    let total = meta.entries.len();
+    let mut entries = Vec::with_capacity(total);
+    let mut files = Vec::with_capacity(total);
+    for (index, entry) in std::mem::take(&mut meta.entries).into_iter().enumerate() {
+        check_cancelled(report)?;
+        report.progress(ProgressEvent::Entry {
+            index,
+            total,
+            name: &entry.name,
+        });
+
+        let entry_path = entry_source(folder, meta, &entry)?;
+
+        if let Some(form) = entry.nested {
+            let contents =
+                rebuild_nested_pac(&nested_folder(&entry_path), form, options, fs, report)
+                    .with_context(|| format!("Rebuilding nested PAC {}", entry.name))?;
+
+            files.push(NamedFile {
+                name: entry.name.clone(),
+                contents,
+            });
+            entries.push(entry);
+        } else if let Ok(contents) = fs.read(&entry_path) {
+            let contents = match &entry.text {
+                Some(form) => text::restore(&contents, form)
+                    .with_context(|| format!("Restoring text entry {}", entry.name))?,
+                None => contents,
+            };
+
+            files.push(NamedFile {
+                name: entry.name.clone(),
+                contents,
+            });
+            entries.push(entry);
+        } else {
+            report.warn(format!(
+                "Failed to read {}! Excluding from PAC file",
+                entry.name
+            ))?;
+        }
+    }
+    meta.entries = entries;
+    assign_file_ids(meta, options);
+
+    Ok(files)
+}
+
+/// Refuses to repack a folder that only holds some of the files it was extracted into,
+/// unless `allow_partial` says to leave the rest out.
+fn ensure_complete(folder: &Path, partial: bool, options: &RepackOptions) -> AResult<()> {
+    if partial && !options.allow_partial {
+        return Err(anyhow::anyhow!(
+            "{} was extracted with --only-images, --only-data or --meta-only and is missing files, pass --allow-partial to repack it anyway",
+            folder.display()
+        ));
+    }
+
+    Ok(())
+}
+
+/// Builds the PAC extracted into `folder` back into the bytes of its parent's entry.
+fn rebuild_nested_pac(
+    folder: &Path,
+    form: NestedPac,
+    options: &RepackOptions,
+    fs: &impl FileSystem,
+    report: &impl Report,
+) -> AResult<Vec<u8>> {
+    let mut meta = match read_meta(folder, fs)? {
+        MetaKind::Pac(meta) => meta,
+        _ => {
+            return Err(anyhow::anyhow!(
+                "{} doesn't hold an extracted PAC",
+                folder.display()
+            ))
+        }
+    };
+
+    check_pac_folder(folder, &mut meta, options, fs, report)?;
+    let files = read_pac_entries(folder, &mut meta, options, fs, report)?;
+    let plain = build_checked_pac(&meta, &files, options)?;
+
+    match form {
+        NestedPac::Plain => Ok(plain),
+        NestedPac::Compressed => compress_rebuilt_pac(folder, plain, report),
+    }
+}
+
+/// Builds the PAC of the folder `folder` out of `meta` and `files`, compressed unless the
+/// original wasn't or `no_compress` is set.
+pub fn rebuild_pac(
+    folder: &Path,
+    meta: &PacMeta,
+    files: &[NamedFile],
+    options: &RepackOptions,
+    report: &impl Report,
+) -> AResult<Vec<u8>> {
+    let plain = build_checked_pac(meta, files, options)?;
+    if meta.uncompressed || options.no_compress {
+        return Ok(plain);
+    }
+
+    compress_rebuilt_pac(folder, plain, report)
+}
+
+/// Builds the uncompressed PAC out of `meta` and `files` and checks the result reads back.
+fn build_checked_pac(
+    meta: &PacMeta,
+    files: &[NamedFile],
+    options: &RepackOptions,
+) -> AResult<Vec<u8>> {
+    let plain = pac::build_pac(meta, files).context("Building PAC")?;
+    pac::check_total_size(&plain).context("Validating rebuilt PAC")?;
+    if !options.no_self_check {
+        check_rebuilt_pac(&plain, meta).context("Re-reading rebuilt PAC")?;
+    }
+
+    Ok(plain)
+}
+
+/// Compresses the archive rebuilt from `folder`, keeping it uncompressed with a warning if
+/// compressing would change its layout.
+fn compress_rebuilt_pac(folder: &Path, plain: Vec<u8>, report: &impl Report) -> AResult<Vec<u8>> {
+    match compress_pac(&plain) {
+        Some(compressed) => Ok(compressed),
+        None => {
+            report.warn(format!(
+                "{} can't be compressed without changing its layout, writing it uncompressed",
+                folder.display()
+            ))?;
+            Ok(plain)
+        }
+    }
+}
+
+/// Parses a freshly built archive back and makes sure it has exactly the entries of `meta`,
+/// so a layout bug surfaces here rather than as a corrupt file on disk.
+fn check_rebuilt_pac(plain: &[u8], meta: &PacMeta) -> AResult<()> {
+    let options = ParseOptions {
+        lossy_names: true,
+        name_terminator: meta.name_terminator,
+        entry_flags: meta.entry_flags,
+        wide_offsets: meta.wide_offsets,
+        repair: false,
+        salvage: false,
+    };
+    let rebuilt = pac::parse_view_with(plain, &options)?;
+
+    if rebuilt.meta.entries.len() != meta.entries.len() {
+        return Err(anyhow::anyhow!(
+            "Rebuilt PAC has {} entries but meta has {}, refusing to write it",
+            rebuilt.meta.entries.len(),
+            meta.entries.len()
+        ));
+    }
+
+    let mismatched: Vec<String> = rebuilt
+        .meta
+        .entries
+        .iter()
+        .zip(&meta.entries)
+        .filter(|(rebuilt, expected)| rebuilt.name_bytes() != expected.name_bytes())
+        .map(|(rebuilt, expected)| format!("{} read back as {}", expected.name, rebuilt.name))
+        .collect();
+
+    if !mismatched.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Rebuilt PAC doesn't match its meta, refusing to write it: {}",
+            mismatched.join(", ")
+        ));
+    }
+
+    Ok(())
+}
+
+/// The file an entry of the PAC being repacked from `folder` is read from.
+pub fn entry_source(folder: &Path, meta: &PacMeta, entry: &PacMetaEntry) -> AResult<PathBuf> {
+    let (path, file) = match (&meta.cas, &entry.hash) {
+        (Some(cas), Some(hash)) => (cas.join(hash), hash.as_str()),
+        _ => (shard::entry_path(folder, entry), entry.file_name()),
+    };
+
+    // meta.json gets shared around, so don't trust it to stay inside the folder
+    if !pac::is_safe_file_name(file) {
+        return Err(anyhow::anyhow!(
+            "Entry {} is stored as {}, which points outside of its folder",
+            entry.name,
+            file
+        ));
+    }
+
+    Ok(path)
+}
+
+/// Indexed and raw HIP images only store 8 bits per channel, so a deeper PNG would be
+/// silently truncated when converted back.
+fn ensure_8bit(image: &DynamicImage, name: &str) -> AResult<()> {
+    let color = image.color();
+    let bits_per_channel = color.bytes_per_pixel() as u16 * 8 / color.channel_count() as u16;
+
+    if bits_per_channel > 8 {
+        return Err(anyhow::anyhow!(
+            "{} has {} bits per channel but this HIP image can only store 8, convert it to 8-bit before repacking",
+            name,
+            bits_per_channel
+        ));
+    }
+
+    Ok(())
+}
+
+/// Puts back the alpha of the `transparent_index` color, which was only cleared for viewing.
+fn restore_transparent_index(
+    palette: &mut [RGBAColor],
+    original: &[RGBAColor],
+    options: &RepackOptions,
+) {
+    if let Some(index) = options.transparent_index {
+        let index = index as usize;
+        if let (Some(color), Some(original)) = (palette.get_mut(index), original.get(index)) {
+            color.alpha = original.alpha;
+        }
+    }
+}
+
+/// Writes `image` to `path` as a PNG.
+pub fn save_png(image: &DynamicImage, path: &Path, fs: &impl FileSink) -> AResult<()> {
+    fs.write(path, &png_bytes(image)?)?;
+
+    Ok(())
+}
+
+fn png_bytes(image: &DynamicImage) -> AResult<Vec<u8>> {
+    let mut bytes = Vec::new();
+    image.write_to(&mut bytes, image::ImageOutputFormat::Png)?;
+
+    Ok(bytes)
+}
+
+fn load_image(path: &Path, fs: &impl FileSource) -> AResult<DynamicImage> {
+    Ok(image::load_from_memory(&fs.read(path)?)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::vfs::MemoryFs;
+
+    /// meta.json of a PAC as unPAC 1.0.3 wrote it.
+    #[derive(Serialize)]
+    enum BaselineMeta {
+        Pac(BBCFPac),
+    }
+
+    const TWO_ENTRIES: &[u8] = include_bytes!("../fuzz/corpus/parse/two_entries.pac");
+    /// 156 distinct colors followed by 100 identical black ones
+    const TRAILING_BLACK: &[u8] = include_bytes!("../fixtures/trailing_black.hpl");
+
+    fn temp_folder(name: &str) -> PathBuf {
+        let folder = std::env::temp_dir().join(format!("unpac-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(&folder).unwrap();
+        folder
+    }
+
+    fn entries(pac: &pac::ParsedPac) -> Vec<(String, u32, Vec<u8>)> {
+        pac.meta
+            .entries
+            .iter()
+            .zip(&pac.files)
+            .map(|(entry, file)| (entry.name.clone(), entry.file_id, file.contents.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn repacks_baseline_meta() {
+        let folder = temp_folder("baseline-meta");
+        let original = pac::parse(TWO_ENTRIES).unwrap();
+        for file in &original.files {
+            fs::write(folder.join(&file.name), &file.contents).unwrap();
+        }
+        let meta = BaselineMeta::Pac(BBCFPac::parse(TWO_ENTRIES).unwrap());
+        fs::write(
+            folder.join(META_FILENAME),
+            serde_json::to_vec(&meta).unwrap(),
+        )
+        .unwrap();
+
+        let repacked = repack_dir(&folder);
+        fs::remove_dir_all(&folder).unwrap();
+
+        let repacked = pac::parse(&plain_pac_bytes(&repacked.unwrap()).unwrap()).unwrap();
+        assert_eq!(entries(&repacked), entries(&original));
+    }
+
+    #[test]
+    fn keeps_trailing_duplicate_palette_colors() {
+        let fs = MemoryFs::new();
+        let folder = Path::new("trailing_black");
+        let repack = || repack_dir_with(folder, &RepackOptions::default(), &fs, &Silent).unwrap();
+
+        extract_hpl_with(
+            TRAILING_BLACK,
+            folder,
+            &ExtractOptions::default(),
+            &fs,
+            &Silent,
+        )
+        .unwrap();
+        assert_eq!(repack(), TRAILING_BLACK);
+
+        // an editor that drops the repeated black leaves the recorded length to restore it
+        let palette_path = folder.join("palette.png");
+        let colors = image_to_colors(&load_image(&palette_path, &fs).unwrap());
+        assert_eq!(colors.len(), 256);
+        save_png(
+            &palette_to_image(&colors[..157]).unwrap(),
+            &palette_path,
+            &fs,
+        )
+        .unwrap();
+        assert_eq!(repack(), TRAILING_BLACK);
+    }
+
+    #[test]
+    fn refuses_meta_only_hpl() {
+        let fs = MemoryFs::new();
+        let folder = Path::new("meta_only");
+        let options = ExtractOptions {
+            meta_only: true,
+            ..ExtractOptions::default()
+        };
+
+        extract_hpl_with(TRAILING_BLACK, folder, &options, &fs, &Silent).unwrap();
+        assert!(!fs.is_file(&folder.join("palette.png")));
+
+        let err = repack_dir_with(folder, &RepackOptions::default(), &fs, &Silent).unwrap_err();
+        assert!(err.to_string().contains("--allow-partial"));
+    }
+
+    fn many_entries() -> Vec<u8> {
+        (0..64u32)
+            .fold(pac::Pac::builder(), |builder, id| {
+                builder.add_file(format!("{}.bin", id), id, vec![id as u8; 0x10])
+            })
+            .build()
+            .unwrap()
+    }
+
+    fn is_cancelled(err: &anyhow::Error) -> bool {
+        matches!(err.downcast_ref::<PacError>(), Some(PacError::Cancelled))
+    }
+
+    #[test]
+    fn cancels_mid_extraction() {
+        let fs = MemoryFs::new();
+        let folder = Path::new("cancelled");
+        let cancel = AtomicBool::new(false);
+        let progress = |event: ProgressEvent| {
+            if let ProgressEvent::Entry { .. } = event {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        };
+
+        let err = extract_pac_with_progress(
+            folder,
+            &many_entries(),
+            folder,
+            &ExtractOptions::default(),
+            &fs,
+            &Silent,
+            &progress,
+            &cancel,
+        )
+        .unwrap_err();
+
+        assert!(is_cancelled(&err));
+        assert!(!fs.is_file(&folder.join(META_FILENAME)));
+    }
+
+    #[test]
+    fn cancels_repack() {
+        let fs = MemoryFs::new();
+        let folder = Path::new("repack_cancelled");
+        extract_pac_with(
+            folder,
+            &many_entries(),
+            folder,
+            &ExtractOptions::default(),
+            &fs,
+            &Silent,
+        )
+        .unwrap();
+
+        let cancel = AtomicBool::new(true);
+        let err = repack_dir_with_progress(
+            folder,
+            &RepackOptions::default(),
+            &fs,
+            &Silent,
+            &|_: ProgressEvent| {},
+            &cancel,
+        )
+        .unwrap_err();
+
+        assert!(is_cancelled(&err));
+    }
+}
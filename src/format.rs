@@ -0,0 +1,50 @@
+use std::str::FromStr;
+
+/// The formats unPAC knows how to extract, identified by their magic bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileKind {
+    Pac,
+    Hip,
+    Hpl,
+}
+
+impl FileKind {
+    pub fn sniff(bytes: &[u8]) -> Option<FileKind> {
+        if bytes.starts_with(b"FPAC") || bytes.starts_with(b"DFASFPAC") {
+            Some(FileKind::Pac)
+        } else if bytes.starts_with(b"HIP\0") {
+            Some(FileKind::Hip)
+        } else if bytes.starts_with(b"HPAL") {
+            Some(FileKind::Hpl)
+        } else {
+            None
+        }
+    }
+
+    /// The kind of file usually stored with `extension`, for files whose magic isn't known.
+    pub fn from_extension(extension: &str) -> Option<FileKind> {
+        match extension.to_ascii_lowercase().as_str() {
+            "pac" => Some(FileKind::Pac),
+            "hip" => Some(FileKind::Hip),
+            "hpl" => Some(FileKind::Hpl),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            FileKind::Pac => "pac",
+            FileKind::Hip => "hip",
+            FileKind::Hpl => "hpl",
+        }
+    }
+}
+
+impl FromStr for FileKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        FileKind::from_extension(s)
+            .ok_or_else(|| anyhow::anyhow!("Expected pac, hip or hpl as format, got {}", s))
+    }
+}
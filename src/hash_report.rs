@@ -0,0 +1,66 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result as AResult;
+use arcsys::bbcf::pac::BBCFPac;
+use rayon::prelude::*;
+use sha2::{Digest, Sha256};
+use unpac::folder::Report;
+
+/// Hashes every entry of every PAC in `input_files` and prints each group of entries
+/// that share identical contents, keyed by their SHA-256. Archives that can't be read are
+/// passed to `report` and left out.
+pub fn print_report(input_files: &[PathBuf], report: &impl Report) -> AResult<()> {
+    let hashed: Vec<Vec<(String, String)>> = input_files
+        .par_iter()
+        .filter(|path| path.is_file())
+        .map(|path| match hash_entries(path) {
+            Ok(entries) => Ok(entries),
+            Err(e) => {
+                report.warn(format!("Failed to hash {}: {}", path.display(), e))?;
+                Ok(Vec::new())
+            }
+        })
+        .collect::<AResult<_>>()?;
+
+    let mut groups: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for (hash, location) in hashed.into_iter().flatten() {
+        groups.entry(hash).or_default().push(location);
+    }
+
+    let mut duplicate_groups = 0;
+    for (hash, mut locations) in groups.into_iter().filter(|(_, l)| l.len() > 1) {
+        locations.sort();
+        duplicate_groups += 1;
+
+        println!("{} ({} entries)", hash, locations.len());
+        for location in locations {
+            println!("    {}", location);
+        }
+    }
+
+    println!("Found {} groups of duplicate entries", duplicate_groups);
+
+    Ok(())
+}
+
+/// Returns a `(hash, "archive: entry")` pair for each entry of the PAC at `path`.
+fn hash_entries(path: &Path) -> AResult<Vec<(String, String)>> {
+    let mut file_buf = Vec::new();
+    File::open(path)?.read_to_end(&mut file_buf)?;
+
+    let pac = BBCFPac::parse(&file_buf)?;
+
+    let hashed = pac
+        .files
+        .par_iter()
+        .map(|entry| {
+            let hash = format!("{:x}", Sha256::digest(&entry.contents));
+            (hash, format!("{}: {}", path.display(), entry.name))
+        })
+        .collect();
+
+    Ok(hashed)
+}
@@ -0,0 +1,88 @@
+//! Raw views of a PAC's header and entry table, for reverse engineering new variants in a
+//! hex editor or on screen.
+
+use std::fmt::Write as _;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result as AResult};
+
+use crate::{create_output, read_file, Run};
+use unpac::pac;
+use unpac::plain_pac_bytes;
+
+/// Handles `--dump-raw-header` and `--hexdump-header` for the PAC at `path`, out of
+/// `input_count` inputs in total.
+pub fn dump_header(path: &Path, input_count: usize, opt: &Run) -> AResult<()> {
+    let file_buf = read_file(path)?;
+    let plain = plain_pac_bytes(&file_buf)?;
+
+    // the header and entry table are everything before the data region
+    let data_start = pac::parse_view_with(&plain, &opt.parse_options())
+        .context("Parsing PAC")?
+        .meta
+        .data_start;
+    let header = &plain[..data_start as usize];
+
+    if let Some(dump_path) = &opt.dump_raw_header {
+        if input_count > 1 {
+            std::fs::create_dir_all(dump_path)
+                .with_context(|| format!("Creating {}", dump_path.display()))?;
+        }
+
+        let dump_path = dump_file(dump_path, path, input_count);
+        let mut out = create_output(&dump_path, opt)
+            .with_context(|| format!("Writing {}", dump_path.display()))?;
+        out.write_all(header)
+            .with_context(|| format!("Writing {}", dump_path.display()))?;
+    }
+
+    if opt.hexdump_header {
+        print!("{}\n{}", path.display(), hexdump(header));
+    }
+
+    Ok(())
+}
+
+/// `dump_path` itself for a single input, otherwise a file named after `input` inside it.
+fn dump_file(dump_path: &Path, input: &Path, input_count: usize) -> PathBuf {
+    if input_count > 1 {
+        let name = input.file_name().unwrap_or_default().to_string_lossy();
+        dump_path.join(format!("{}.header.bin", name))
+    } else {
+        dump_path.to_path_buf()
+    }
+}
+
+/// Formats `bytes` as offset, hex and ASCII columns of 16 bytes each.
+fn hexdump(bytes: &[u8]) -> String {
+    let mut dump = String::new();
+
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        let _ = write!(dump, "{:08x}  ", row * 16);
+
+        for i in 0..16 {
+            match chunk.get(i) {
+                Some(b) => {
+                    let _ = write!(dump, "{:02x} ", b);
+                }
+                None => dump.push_str("   "),
+            }
+            if i == 7 {
+                dump.push(' ');
+            }
+        }
+
+        dump.push_str(" |");
+        dump.extend(chunk.iter().map(|&b| {
+            if b.is_ascii_graphic() || b == b' ' {
+                b as char
+            } else {
+                '.'
+            }
+        }));
+        dump.push_str("|\n");
+    }
+
+    dump
+}
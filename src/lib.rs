@@ -0,0 +1,57 @@
+//! The format handling behind unPAC, free of CLI concerns so it also builds for targets
+//! like `wasm32-unknown-unknown`. Everything but `folder` and the folder layouts it uses
+//! (`shard`, `sidecar`) works on byte slices and returns bytes or in-memory images; those
+//! go through a `vfs::FileSystem`, and `pac::write_pac_streaming` reads entries and writes
+//! the archive through whatever `Read` and `Write` it is handed.
+
+use std::borrow::Cow;
+
+use anyhow::Result as AResult;
+use arcsys::bbcf::hip::BBCFHip;
+use arcsys::bbcf::pac::BBCFPac;
+
+pub mod convert;
+pub mod folder;
+pub mod format;
+pub mod pac;
+pub mod shard;
+pub mod sidecar;
+pub mod text;
+pub mod vfs;
+
+pub use convert::hip_to_image;
+pub use folder::{extract_hip, extract_hpl, extract_pac, repack_dir};
+pub use pac::{build_pac, parse};
+
+/// The in-tree parser only understands plain FPAC data, so compressed archives are
+/// unpacked through `arcsys` first.
+pub fn plain_pac_bytes(input: &[u8]) -> AResult<Cow<[u8]>> {
+    if input.starts_with(pac::HEADER_MAGIC) {
+        Ok(Cow::Borrowed(input))
+    } else {
+        Ok(Cow::Owned(BBCFPac::parse(input)?.to_bytes()))
+    }
+}
+
+/// Compresses the plain FPAC archive in `plain`, or returns `None` if the compressed form
+/// wouldn't unpack to exactly these bytes.
+///
+/// `arcsys` writes the archive out again in its own layout as it compresses it, so an
+/// archive relying on anything that layout can't express, like a moved data region, entry
+/// flags or shared data, can only be stored uncompressed.
+pub fn compress_pac(plain: &[u8]) -> Option<Vec<u8>> {
+    let compressed = BBCFPac::parse(plain).ok()?.to_bytes_compressed();
+    let unpacked = plain_pac_bytes(&compressed).ok()?;
+
+    Some(compressed).filter(|_| *unpacked == *plain)
+}
+
+/// Decodes the HIP file in `input` into a PNG.
+pub fn hip_to_png(input: &[u8]) -> AResult<Vec<u8>> {
+    let hip = BBCFHip::parse(input)?;
+
+    let mut png = Vec::new();
+    hip_to_image(hip.image)?.write_to(&mut png, image::ImageOutputFormat::Png)?;
+
+    Ok(png)
+}
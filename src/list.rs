@@ -0,0 +1,150 @@
+use std::fmt::Write as _;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{Context, Result as AResult};
+use arcsys::bbcf::hip::{BBCFHip, BBCFHipImage};
+
+use crate::{color, file_kind, read_file, Run};
+use unpac::format::FileKind;
+use unpac::pac::{self, PacMetaEntry};
+use unpac::plain_pac_bytes;
+
+#[derive(Debug, Clone, Copy)]
+pub enum SortKey {
+    Name,
+    Id,
+    Size,
+    Offset,
+}
+
+impl SortKey {
+    pub const VARIANTS: &'static [&'static str] = &["name", "id", "size", "offset"];
+}
+
+impl FromStr for SortKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> AResult<Self> {
+        match s {
+            "name" => Ok(SortKey::Name),
+            "id" => Ok(SortKey::Id),
+            "size" => Ok(SortKey::Size),
+            "offset" => Ok(SortKey::Offset),
+            _ => Err(anyhow::anyhow!("Unknown sort key {}", s)),
+        }
+    }
+}
+
+/// Reads the entry table of the PAC at `path`, ordered as requested by `opt`.
+pub fn list_entries(path: &Path, opt: &Run) -> AResult<Vec<PacMetaEntry>> {
+    pac_entries(&read_file(path)?, opt)
+}
+
+fn pac_entries(file_buf: &[u8], opt: &Run) -> AResult<Vec<PacMetaEntry>> {
+    let plain = plain_pac_bytes(file_buf)?;
+    let pac = pac::parse_view_with(&plain, &opt.parse_options())?;
+    let mut entries = pac.meta.entries;
+
+    if let Some(key) = opt.sort {
+        sort_entries(&mut entries, key);
+    }
+
+    if opt.descending {
+        entries.reverse();
+    }
+
+    Ok(entries)
+}
+
+/// Prints the entries of the PAC at `path`, or the dimensions of the HIP at `path`, as a
+/// single block, so listings of different files don't interleave when run in parallel.
+pub fn list_file(path: &Path, opt: &Run) -> AResult<()> {
+    let file_buf = read_file(path)?;
+
+    let listing = match file_kind(path, &file_buf) {
+        Some(FileKind::Pac) => pac_listing(path, &file_buf, opt)?,
+        Some(FileKind::Hip) => hip_listing(path, &file_buf).context("Parsing HIP")?,
+        _ => return Err(anyhow::anyhow!("Only PAC and HIP files can be listed")),
+    };
+
+    print!("{}", listing);
+
+    Ok(())
+}
+
+fn pac_listing(path: &Path, file_buf: &[u8], opt: &Run) -> AResult<String> {
+    let mut entries = pac_entries(file_buf, opt)?;
+    let total = entries.len();
+
+    if let Some(head) = opt.head {
+        entries.truncate(head);
+    }
+
+    if let Some(tail) = opt.tail {
+        entries.drain(..entries.len().saturating_sub(tail));
+    }
+
+    let mut listing = if entries.len() == total {
+        format!("{} ({} entries)\n", path.display(), total)
+    } else {
+        format!(
+            "{} ({} of {} entries)\n",
+            path.display(),
+            entries.len(),
+            total
+        )
+    };
+    writeln!(listing, "{:>8} {:>10} {:>10}  name", "id", "offset", "size")?;
+    for entry in &entries {
+        writeln!(
+            listing,
+            "{:>8} {:>#10x} {}  {}",
+            entry.file_id,
+            entry.offset,
+            color::size(opt, format!("{:>10}", entry.size)),
+            color::file_name(opt, &entry.name)
+        )?;
+    }
+
+    let total_size: u64 = entries.iter().map(|e| e.size).sum();
+    writeln!(
+        listing,
+        "{:>8} {:>10} {}  total",
+        "",
+        "",
+        color::size(opt, format!("{:>10}", total_size))
+    )?;
+
+    Ok(listing)
+}
+
+fn hip_listing(path: &Path, file_buf: &[u8]) -> AResult<String> {
+    let hip = BBCFHip::parse(file_buf)?;
+
+    let description = match &hip.image {
+        BBCFHipImage::Indexed {
+            width,
+            height,
+            data,
+        } => format!(
+            "indexed, {}x{}, {} palette colors",
+            width,
+            height,
+            data.palette.len()
+        ),
+        BBCFHipImage::Raw { width, height, .. } => format!("raw, {}x{}", width, height),
+        BBCFHipImage::Luma { width, height, .. } => format!("luma, {}x{}", width, height),
+    };
+
+    Ok(format!("{}: {}\n", path.display(), description))
+}
+
+fn sort_entries(entries: &mut [PacMetaEntry], key: SortKey) {
+    match key {
+        SortKey::Name => entries.sort_by(|a, b| a.name.cmp(&b.name)),
+        SortKey::Id => entries.sort_by_key(|e| e.file_id),
+        SortKey::Size => entries.sort_by_key(|e| e.size),
+        SortKey::Offset => entries.sort_by_key(|e| e.offset),
+    }
+}
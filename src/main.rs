@@ -1,348 +1,1427 @@
 use std::fs::File;
-use std::io::{prelude::*, BufReader};
-use std::path::PathBuf;
-
-use anyhow::Result as AResult;
+use std::io::{prelude::*, IsTerminal};
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use anyhow::{Context, Result as AResult};
 use arcsys::bbcf::hip::{BBCFHip, BBCFHipImage};
 use arcsys::bbcf::hpl::BBCFHpl;
-use arcsys::bbcf::pac::{BBCFPac, BBCFPacEntry};
-use arcsys::{IndexedImage, RGBAColor};
-use image::{DynamicImage, GenericImageView, GrayImage, RgbaImage};
+use image::DynamicImage;
+use memmap2::Mmap;
 use rayon::prelude::*;
 use structopt::StructOpt;
 
-const META_FILENAME: &str = "meta.json";
+use unpac::convert::indexed_to_rgba;
+use unpac::folder::{
+    self, apply_header_overrides, find_meta, format_size, is_selected, read_meta, save_png,
+    write_meta, ExtractOptions, MetaKind, RepackOptions, Report, COMPRESSED_META_FILENAME,
+    META_FILENAME,
+};
+use unpac::format::FileKind;
+use unpac::pac::{self, NamedFile, PacMeta};
+use unpac::plain_pac_bytes;
+use unpac::vfs::{FileSink, FileSource, FileSystem};
+use unpac::{shard, sidecar, text};
+
+mod color;
+mod detect;
+mod diff_meta;
+mod disk;
+mod hash_report;
+mod header_dump;
+mod list;
+mod numbered;
+mod regen;
+mod server;
+mod tui;
+mod verify;
+
+/// Keeps prompts from different worker threads from interleaving
+static PROMPT_LOCK: Mutex<()> = Mutex::new(());
 
 #[derive(StructOpt, Debug)]
 #[structopt(name = "unPAC")]
 struct Run {
+    /// Files to extract and extraction folders to repack. Any other folder is searched for
+    /// PAC, HIP and HPL files to extract
     input_files: Vec<PathBuf>,
+
+    /// Print groups of PAC entries with identical contents across all inputs instead of extracting
+    #[structopt(long)]
+    hash_report: bool,
+
+    /// Compare the metadata of two extraction folders instead of repacking them
+    #[structopt(long)]
+    diff_meta: bool,
+
+    /// Write the header and entry table of each input PAC, everything before its data, to
+    /// this file, or into this folder as <name>.header.bin when given several inputs
+    #[structopt(long)]
+    dump_raw_header: Option<PathBuf>,
+
+    /// Print a hex dump of the header and entry table of each input PAC
+    #[structopt(long)]
+    hexdump_header: bool,
+
+    /// Try every known PAC layout on each input and report which ones read sanely
+    #[structopt(long)]
+    force_variant_detect: bool,
+
+    /// Treat every warning (skipped files, overwritten outputs, ...) as a fatal error
+    #[structopt(long)]
+    strict: bool,
+
+    /// Rename a single entry inside each input PAC in place, given as `old=new`
+    #[structopt(long, parse(try_from_str = parse_rename))]
+    rename: Option<(String, String)>,
+
+    /// Print the entries of each input PAC, or the dimensions of each HIP, instead of
+    /// extracting it
+    #[structopt(short, long)]
+    list: bool,
+
+    /// Order listed entries by the given key instead of their order in the archive
+    #[structopt(long, possible_values = list::SortKey::VARIANTS)]
+    sort: Option<list::SortKey>,
+
+    /// List entries in descending order
+    #[structopt(long)]
+    descending: bool,
+
+    /// Only list the first n entries, after sorting
+    #[structopt(long)]
+    head: Option<usize>,
+
+    /// Only list the last n entries, after sorting
+    #[structopt(long)]
+    tail: Option<usize>,
+
+    /// Extract over existing non-empty folders without asking first
+    #[structopt(short = "y", long, visible_alias = "yes")]
+    overwrite: bool,
+
+    /// Write PAC entries into this content-addressed store, named by their SHA-256
+    #[structopt(long)]
+    cas: Option<PathBuf>,
+
+    /// Decode PAC entry names that aren't valid UTF-8 with replacement characters instead of failing
+    #[structopt(long)]
+    lossy_names: bool,
+
+    /// Byte ending PAC entry names within their field (`none` if names fill the whole field)
+    #[structopt(long, default_value = "0")]
+    name_terminator: pac::NameTerminator,
+
+    /// Permissions given to extracted files on Unix, in octal
+    #[cfg(unix)]
+    #[structopt(long, default_value = "644", parse(try_from_str = parse_mode))]
+    mode: u32,
+
+    /// Read PAC entries with an extra flags field after their size, as used by some variants
+    #[structopt(long)]
+    entry_flags: bool,
+
+    /// Read PAC entries with 64-bit offset and size fields, as used by archives past 4 GiB
+    #[structopt(long)]
+    wide_offsets: bool,
+
+    /// Recover PACs whose header string_size is off by one alignment step
+    #[structopt(long)]
+    repair: bool,
+
+    /// Extract the complete entries of a truncated PAC, such as a cut off download, instead
+    /// of failing on the first entry that runs past its end
+    #[structopt(long)]
+    salvage: bool,
+
+    /// Palette index of indexed HIP images that is exported fully transparent and gets its
+    /// original alpha back on repack
+    #[structopt(long)]
+    transparent_index: Option<u8>,
+
+    /// HPL file whose palette indexed HIP images are also rendered with, into a preview.png
+    /// that repacking ignores
+    #[structopt(long, value_name = "FILE.hpl")]
+    palette: Option<PathBuf>,
+
+    /// Write indexed HIP images as a grayscale map of their palette indices instead of in
+    /// color. Repacking a colored image needs every pixel to match a palette color
+    #[structopt(long)]
+    raw_indices: bool,
+
+    /// Write indexed HIP images as a single indexed-color image.png that carries the palette
+    /// itself, instead of image.png next to a palette.png strip
+    #[structopt(long, conflicts_with = "raw-indices")]
+    indexed_png: bool,
+
+    /// Only extract PAC entries that are HIP images
+    #[structopt(long, conflicts_with = "only-data")]
+    only_images: bool,
+
+    /// Only extract PAC entries that are not HIP images
+    #[structopt(long)]
+    only_data: bool,
+
+    /// Remove a folder created by a failed extraction instead of leaving it half-written
+    #[structopt(long)]
+    clean_on_error: bool,
+
+    /// Read up to this many input files ahead on a separate thread while others are being extracted
+    #[structopt(long, default_value = "0")]
+    prefetch: usize,
+
+    /// Extract or repack at most this many inputs at once, leaving the remaining threads to
+    /// the work within each of them
+    #[structopt(long)]
+    parallel_archives: Option<NonZeroUsize>,
+
+    /// Repack PACs by copying each entry straight from disk instead of loading them all
+    /// first, writing them uncompressed
+    #[structopt(long)]
+    stream: bool,
+
+    /// Only write meta.json for each input, skipping entry files and images
+    #[structopt(long)]
+    meta_only: bool,
+
+    /// Repack PAC folders extracted with --only-images, --only-data or --meta-only, leaving
+    /// out the entries that weren't extracted
+    #[structopt(long)]
+    allow_partial: bool,
+
+    /// Use the header values a game expects for repacked PACs (padding, `unknown` and data
+    /// alignment), one of: bbcf
+    #[structopt(long)]
+    target: Option<pac::Target>,
+
+    /// Start the data region of repacked PACs on a multiple of this many bytes, e.g. 0x800
+    #[structopt(long, parse(try_from_str = parse_alignment))]
+    data_align: Option<u32>,
+
+    /// Store every entry with identical contents only once in repacked PACs, pointing them
+    /// all at the same data, rather than just those that shared data in the original
+    #[structopt(long)]
+    dedup_data: bool,
+
+    /// Extract PAC entries as numbered 00000.bin files with an index.csv of their names,
+    /// ids and sizes instead of a meta.json. Folders with an index.csv are repacked from it
+    #[structopt(long)]
+    numbered: bool,
+
+    /// Rebuild a PAC meta.json from the files in each input folder instead of repacking it
+    #[structopt(long)]
+    regen_meta: bool,
+
+    /// Write a small `<entry>.meta.json` next to each extracted PAC entry instead of listing them all in meta.json
+    #[structopt(long)]
+    split_meta: bool,
+
+    /// Extract PAC entries into this many shard folders balanced by total size, each with a
+    /// shard.json listing its entries, so separate workers can process one each
+    #[structopt(long, conflicts_with_all = &["split-meta", "cas"])]
+    shards: Option<NonZeroU32>,
+
+    /// Extract entries matching a glob as UTF-8 text with LF line endings, restoring the
+    /// given encoding and endings on repack, e.g. `*.txt=shift_jis`. Can be repeated
+    #[structopt(long, number_of_values = 1)]
+    text_entries: Vec<text::TextRule>,
+
+    /// Extract PAC entries that are PACs themselves into a folder of their own, rebuilding
+    /// them into their parent on repack
+    #[structopt(long)]
+    recursive: bool,
+
+    /// How many levels of PACs inside PACs `--recursive` extracts
+    #[structopt(long, default_value = "8")]
+    max_depth: usize,
+
+    /// Extract into <DIR>/<name>/ and repack into <DIR>/<name>.pac instead of next to each input
+    #[structopt(long, value_name = "DIR")]
+    output_dir: Option<PathBuf>,
+
+    /// Extract a file read from stdin into a folder named `stdin`, instead of input files
+    #[structopt(long, conflicts_with = "input-files")]
+    stdin: bool,
+
+    /// Format of the file read with `--stdin`, for when its magic bytes aren't recognized
+    #[structopt(long, requires = "stdin", possible_values = &["pac", "hip", "hpl"])]
+    format: Option<FileKind>,
+
+    /// Write the file repacked from a single input folder to stdout instead of next to it
+    #[structopt(long, conflicts_with = "stream")]
+    to_stdout: bool,
+
+    /// Write meta.json gzip-compressed, as meta.json.gz
+    #[structopt(long)]
+    compress_meta: bool,
+
+    /// Write repacked PACs uncompressed, even if the original archive was compressed
+    #[structopt(long)]
+    no_compress: bool,
+
+    /// Skip parsing each rebuilt PAC back to check its entries before writing it
+    #[structopt(long)]
+    no_self_check: bool,
+
+    /// Give the entries of repacked PACs sequential file_ids in table order, after dropping
+    /// any whose file is missing, instead of keeping the ids from meta.json
+    #[structopt(long)]
+    renumber_ids: bool,
+
+    /// Re-read every extracted PAC entry and check it matches what was extracted
+    #[structopt(long)]
+    verify_write: bool,
+
+    /// Extract and repack each input file in memory and report whether the result matches
+    /// the original byte for byte, instead of extracting it
+    #[structopt(long, conflicts_with_all = &["stream", "to-stdout"])]
+    verify: bool,
+
+    /// Bytes that must still be free on the target disk after extracting a PAC, e.g. 512M
+    #[structopt(long, default_value = "0", parse(try_from_str = parse_size))]
+    min_free_space: u64,
+
+    /// Extract even when the target disk looks too full for it
+    #[structopt(long)]
+    force: bool,
+
+    /// Exit as soon as all files are done instead of waiting for enter to be pressed
+    #[structopt(long)]
+    no_pause: bool,
+
+    /// Don't print a preview of each PAC's entry count and size before extracting it, or a
+    /// progress line as each input is done
+    #[structopt(short, long)]
+    quiet: bool,
+
+    /// Browse the entries of each input PAC in an interactive terminal UI
+    #[structopt(long)]
+    tui: bool,
+
+    /// Serve newline-delimited JSON commands from stdin instead of processing input files
+    #[structopt(long)]
+    server: bool,
+
+    /// Always color output, even when it isn't going to a terminal
+    #[structopt(long, conflicts_with = "no-color")]
+    color: bool,
+
+    /// Never color output, as also requested by setting NO_COLOR
+    #[structopt(long)]
+    no_color: bool,
+}
+
+impl Run {
+    fn parse_options(&self) -> pac::ParseOptions {
+        pac::ParseOptions {
+            lossy_names: self.lossy_names,
+            name_terminator: self.name_terminator,
+            entry_flags: self.entry_flags,
+            wide_offsets: self.wide_offsets,
+            repair: self.repair,
+            salvage: self.salvage,
+        }
+    }
+
+    fn extract_options(&self) -> ExtractOptions {
+        ExtractOptions {
+            parse: self.parse_options(),
+            only_images: self.only_images,
+            only_data: self.only_data,
+            meta_only: self.meta_only,
+            compress_meta: self.compress_meta,
+            split_meta: self.split_meta,
+            shards: self.shards,
+            cas: self.cas.clone(),
+            text_entries: self.text_entries.clone(),
+            recursive: self.recursive,
+            max_depth: self.max_depth,
+            verify_write: self.verify_write,
+            min_free_space: Some(self.min_free_space).filter(|_| !self.force),
+            raw_indices: self.raw_indices,
+            indexed_png: self.indexed_png,
+            transparent_index: self.transparent_index,
+        }
+    }
+
+    fn repack_options(&self) -> RepackOptions {
+        RepackOptions {
+            target: self.target,
+            data_align: self.data_align,
+            dedup_data: self.dedup_data,
+            no_compress: self.no_compress,
+            no_self_check: self.no_self_check,
+            renumber_ids: self.renumber_ids,
+            allow_partial: self.allow_partial,
+            transparent_index: self.transparent_index,
+        }
+    }
+}
+
+fn parse_rename(s: &str) -> AResult<(String, String)> {
+    let mut split = s.splitn(2, '=');
+
+    match (split.next(), split.next()) {
+        (Some(old), Some(new)) if !old.is_empty() => Ok((old.to_string(), new.to_string())),
+        _ => Err(anyhow::anyhow!("Expected a rename in the form `old=new`")),
+    }
+}
+
+#[cfg(unix)]
+fn parse_mode(s: &str) -> AResult<u32> {
+    let mode = u32::from_str_radix(s, 8)?;
+
+    if mode > 0o7777 {
+        return Err(anyhow::anyhow!("{} is not a valid file mode", s));
+    }
+
+    Ok(mode)
+}
+
+/// Parses a byte count with an optional `K`, `M` or `G` binary suffix.
+fn parse_size(s: &str) -> AResult<u64> {
+    let (digits, unit) = match s.char_indices().last() {
+        Some((i, 'K')) | Some((i, 'k')) => (&s[..i], 1 << 10),
+        Some((i, 'M')) | Some((i, 'm')) => (&s[..i], 1 << 20),
+        Some((i, 'G')) | Some((i, 'g')) => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+
+    let count: u64 = digits
+        .parse()
+        .map_err(|_| anyhow::anyhow!("Invalid size {}", s))?;
+
+    count
+        .checked_mul(unit)
+        .ok_or_else(|| anyhow::anyhow!("Size {} is too large", s))
+}
+
+fn parse_alignment(s: &str) -> AResult<u32> {
+    let alignment = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16)?,
+        None => s.parse()?,
+    };
+
+    if !alignment.is_power_of_two() {
+        return Err(anyhow::anyhow!("Alignment {} is not a power of two", s));
+    }
+
+    Ok(alignment)
 }
 
 fn main() {
-    if let Err(e) = run() {
-        println!("ERROR: {}", e.to_string());
+    match run() {
+        Ok(0) => {}
+        Ok(_) => std::process::exit(1),
+        Err(e) => {
+            println!("ERROR: {:#}", e);
+            std::process::exit(1);
+        }
     }
 }
 
-fn run() -> AResult<()> {
+/// Runs unPAC, returning how many input files failed to extract or repack.
+fn run() -> AResult<usize> {
     let opt = Run::from_args();
 
-    println!("unPAC - Written by Pangaea");
+    log(&opt, "unPAC - Written by Pangaea");
 
-    let input_files: Vec<PathBuf> = opt.input_files;
+    let input_files = &opt.input_files;
 
-    input_files.into_par_iter().for_each(|path| {
-        if path.is_file() {
-            let mut file_buf = Vec::new();
-            if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_end(&mut file_buf)) {
-                println!("Error reading file {}: {}", path.display(), e);
-                return;
-            };
+    if opt.server {
+        return server::serve(&opt).map(|()| 0);
+    }
 
-            let res = match path.extension().map(|e| e.to_str()).flatten() {
-                Some("pac") => handle_pac(file_buf, path.with_extension("")),
-                Some("hip") => handle_hip(file_buf, path.with_extension("")),
-                Some("hpl") => handle_hpl(file_buf, path.with_extension("")),
-                _ => Err(anyhow::anyhow!(
-                    "File either has no extension or is unrecognized"
-                )),
-            };
+    if opt.stdin {
+        let mut file_buf = Vec::new();
+        std::io::stdin()
+            .lock()
+            .read_to_end(&mut file_buf)
+            .context("Reading stdin")?;
+
+        let path = match opt.format {
+            Some(kind) => PathBuf::from(format!("stdin.{}", kind.extension())),
+            None => PathBuf::from("stdin"),
+        };
+        extract_file(&path, &file_buf, &opt)?;
+
+        log(&opt, "Done!");
+
+        return Ok(0);
+    }
+
+    if opt.to_stdout && !matches!(input_files.as_slice(), [folder] if folder.is_dir()) {
+        return Err(anyhow::anyhow!(
+            "--to-stdout needs exactly one folder to repack"
+        ));
+    }
+
+    if opt.tui {
+        for path in input_files.iter().filter(|p| p.is_file()) {
+            tui::browse(path, &opt).with_context(|| format!("Browsing {}", path.display()))?;
+        }
+
+        return Ok(0);
+    }
+
+    if opt.hash_report {
+        hash_report::print_report(input_files, &Cli(&opt))?;
+
+        println!("Done!");
+        pause(&opt);
+
+        return Ok(0);
+    }
+
+    if opt.diff_meta {
+        match input_files.as_slice() {
+            [old, new] => diff_meta::print_diff(old, new, &opt)?,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "--diff-meta needs exactly two extraction folders"
+                ))
+            }
+        }
+
+        println!("Done!");
+        pause(&opt);
+
+        return Ok(0);
+    }
+
+    if opt.dump_raw_header.is_some() || opt.hexdump_header {
+        for path in input_files.iter().filter(|p| p.is_file()) {
+            if let Err(e) = header_dump::dump_header(path, input_files.len(), &opt) {
+                println!(
+                    "{}",
+                    color::error(&opt, format!("Error dumping header of {}:", path.display()))
+                );
+                println!("{:#}", e);
+            }
+        }
+
+        println!("Done!");
+        pause(&opt);
+
+        return Ok(0);
+    }
+
+    if opt.force_variant_detect {
+        input_files.par_iter().for_each(|path| {
+            if let Err(e) = detect::print_variants(path) {
+                println!(
+                    "{}",
+                    color::error(
+                        &opt,
+                        format!("Error detecting variant of {}:", path.display())
+                    )
+                );
+                println!("{:#}", e);
+            }
+        });
 
-            if let Err(e) = res {
-                println!("Error extracting {}:", path.display());
-                println!("{}", e);
+        println!("Done!");
+        pause(&opt);
+
+        return Ok(0);
+    }
+
+    if opt.list {
+        input_files.par_iter().for_each(|path| {
+            if let Err(e) = list::list_file(path, &opt) {
+                println!(
+                    "{}",
+                    color::error(&opt, format!("Error listing {}:", path.display()))
+                );
+                println!("{:#}", e);
+            }
+        });
+
+        println!("Done!");
+        pause(&opt);
+
+        return Ok(0);
+    }
+
+    if opt.regen_meta {
+        input_files
+            .par_iter()
+            .filter(|p| p.is_dir())
+            .for_each(|path| {
+                if let Err(e) = regen::regen_meta(path, &opt) {
+                    println!(
+                        "{}",
+                        color::error(
+                            &opt,
+                            format!("Error regenerating meta for {}:", path.display())
+                        )
+                    );
+                    println!("{:#}", e);
+                }
+            });
+
+        println!("Done!");
+        pause(&opt);
+
+        return Ok(0);
+    }
+
+    if let Some((old, new)) = &opt.rename {
+        input_files.par_iter().for_each(|path| {
+            if let Err(e) = rename_entry(path, old, new, &opt) {
+                println!(
+                    "{}",
+                    color::error(&opt, format!("Error renaming entry in {}:", path.display()))
+                );
+                println!("{:#}", e);
             }
-        } else if path.is_dir() {
-            if let Err(e) = repack_dir(path) {
-                println!("Error: {}", e)
+        });
+
+        println!("Done!");
+        pause(&opt);
+
+        return Ok(0);
+    }
+
+    let input_files = &expand_input_folders(input_files, &opt);
+
+    if opt.verify {
+        let failed = AtomicUsize::new(0);
+
+        input_files
+            .par_iter()
+            .filter(|p| p.is_file())
+            .for_each(|path| match verify::verify_file(path, &opt) {
+                Ok(true) => {}
+                Ok(false) => {
+                    failed.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    println!(
+                        "{}",
+                        color::error(&opt, format!("Error verifying {}:", path.display()))
+                    );
+                    println!("{:#}", e);
+                    failed.fetch_add(1, Ordering::Relaxed);
+                }
+            });
+
+        println!("Done!");
+        pause(&opt);
+
+        return Ok(failed.into_inner());
+    }
+
+    let succeeded = AtomicUsize::new(0);
+    let failed = AtomicUsize::new(0);
+    // files are extracted and folders repacked, each in a pass of their own
+    let (files, folders): (Vec<PathBuf>, Vec<PathBuf>) = input_files
+        .iter()
+        .filter(|p| p.is_file() || p.is_dir())
+        .cloned()
+        .partition(|p| p.is_file());
+    let total = files.len() + folders.len();
+    let done = AtomicUsize::new(0);
+    let tally = |path: &Path, ok: bool| {
+        let counter = if ok { &succeeded } else { &failed };
+        counter.fetch_add(1, Ordering::Relaxed);
+
+        let index = done.fetch_add(1, Ordering::Relaxed) + 1;
+        if !opt.quiet {
+            let action = match (ok, path.is_dir()) {
+                (false, _) => "failed",
+                (true, true) => "repacked",
+                (true, false) => "extracted",
             };
+            // a single call per line, so lines of different threads don't interleave
+            log(
+                &opt,
+                &format!("[{}/{}] {} {}", index, total, action, path.display()),
+            );
+        }
+    };
+
+    let process_file = |path: &Path, read: std::io::Result<InputFile>| {
+        let file_buf = match read {
+            Ok(file_buf) => file_buf,
+            Err(e) => {
+                println!(
+                    "{} {}",
+                    color::error(&opt, format!("Error reading file {}:", path.display())),
+                    e
+                );
+                tally(path, false);
+                return;
+            }
+        };
+
+        if let Err(e) = extract_file(path, &file_buf, &opt) {
+            println!(
+                "{}",
+                color::error(&opt, format!("Error extracting {}:", path.display()))
+            );
+            println!("{:#}", e);
+            tally(path, false);
+        } else {
+            tally(path, true);
+        }
+    };
+
+    if opt.prefetch > 0 {
+        let (sender, receiver) = std::sync::mpsc::sync_channel(opt.prefetch);
+        let files = &files;
+
+        // a single reader thread keeps up to `prefetch` files in memory ahead of the workers,
+        // so waiting on slow disks overlaps with parsing instead of blocking it
+        std::thread::scope(|s| {
+            s.spawn(move || {
+                for path in files {
+                    let read = read_file(path).map(InputFile::Read);
+                    if sender.send((path, read)).is_err() {
+                        break;
+                    }
+                }
+            });
+
+            match opt.parallel_archives {
+                Some(limit) => {
+                    let receiver = Mutex::new(receiver);
+                    for_each_limited(
+                        limit.get(),
+                        || receiver.lock().unwrap().recv().ok(),
+                        |(path, read)| process_file(path, read),
+                    );
+                }
+                None => receiver
+                    .into_iter()
+                    .par_bridge()
+                    .for_each(|(path, read)| process_file(path, read)),
+            }
+        });
+    } else {
+        for_each_archive(&files, &opt, |path| process_file(path, open_input(path)));
+    }
+
+    for_each_archive(&folders, &opt, |path| {
+        if let Err(e) = repack_dir(path, &opt, &disk::Disk(&opt)) {
+            log(
+                &opt,
+                &color::error(&opt, format!("Error repacking {}:", path.display())),
+            );
+            log(&opt, &format!("{:#}", e));
+            tally(path, false);
+        } else {
+            tally(path, true);
         }
     });
 
-    println!("Done!");
-    pause();
+    let failed = failed.into_inner();
+    let summary = format!("{} failed", failed);
+    log(
+        &opt,
+        &format!(
+            "Done! {} succeeded, {}",
+            succeeded.into_inner(),
+            if failed > 0 {
+                color::error(&opt, summary)
+            } else {
+                summary
+            }
+        ),
+    );
+    pause(&opt);
 
-    Ok(())
+    Ok(failed)
+}
+
+/// Replaces every input folder that isn't an extraction folder with the PAC, HIP and HPL
+/// files found anywhere below it, so whole folders of archives can be extracted at once.
+fn expand_input_folders(paths: &[PathBuf], opt: &Run) -> Vec<PathBuf> {
+    let fs = disk::Disk(opt);
+    let mut expanded = Vec::with_capacity(paths.len());
+
+    for path in paths {
+        if path.is_dir() && !is_extraction_folder(path, &fs) {
+            let found = find_inputs(path, &fs);
+
+            // with nothing to extract, keep the folder so repacking it explains what's missing
+            if !found.is_empty() {
+                expanded.extend(found);
+                continue;
+            }
+        }
+
+        expanded.push(path.clone());
+    }
+
+    expanded
+}
+
+fn is_extraction_folder(path: &Path, fs: &impl FileSource) -> bool {
+    find_meta(path, fs).is_some() || fs.is_file(&path.join(numbered::INDEX_FILENAME))
+}
+
+/// The files with a PAC, HIP or HPL extension below `folder`, skipping extraction folders
+/// and anything that can't be read.
+fn find_inputs(folder: &Path, fs: &impl FileSource) -> Vec<PathBuf> {
+    let mut children: Vec<PathBuf> = match std::fs::read_dir(folder) {
+        Ok(dir) => dir.filter_map(|e| e.ok()).map(|e| e.path()).collect(),
+        Err(_) => return Vec::new(),
+    };
+    children.sort();
+
+    let mut found = Vec::new();
+    for child in children {
+        if child.is_dir() {
+            if !is_extraction_folder(&child, fs) {
+                found.extend(find_inputs(&child, fs));
+            }
+        } else if child
+            .extension()
+            .and_then(|e| e.to_str())
+            .and_then(FileKind::from_extension)
+            .is_some()
+        {
+            found.push(child);
+        }
+    }
+
+    found
+}
+
+/// Runs `f` over `paths` in parallel, with at most `--parallel-archives` of them in flight
+/// at once so only that many archive buffers are held in memory.
+fn for_each_archive<F>(paths: &[PathBuf], opt: &Run, f: F)
+where
+    F: Fn(&PathBuf) + Send + Sync,
+{
+    match opt.parallel_archives {
+        Some(limit) => {
+            let next = AtomicUsize::new(0);
+            for_each_limited(
+                limit.get(),
+                || paths.get(next.fetch_add(1, Ordering::Relaxed)),
+                f,
+            );
+        }
+        None => paths.par_iter().for_each(f),
+    }
+}
+
+/// Runs `f` on every item `next` hands out until it runs dry, on at most `limit` tasks of
+/// the rayon pool at once. Each task moves on to the next item as soon as it's done with
+/// one, and threads not busy with an item pick up the work within the others.
+fn for_each_limited<T, N, F>(limit: usize, next: N, f: F)
+where
+    N: Fn() -> Option<T> + Sync,
+    F: Fn(T) + Sync,
+{
+    rayon::scope(|s| {
+        for _ in 0..limit {
+            s.spawn(|_| {
+                while let Some(item) = next() {
+                    f(item);
+                }
+            });
+        }
+    });
+}
+
+fn read_file(path: &Path) -> std::io::Result<Vec<u8>> {
+    let mut file_buf = Vec::new();
+    File::open(path)?.read_to_end(&mut file_buf)?;
+
+    Ok(file_buf)
+}
+
+/// The contents of an input file, mapped or read into memory.
+enum InputFile {
+    Mapped(Mmap),
+    Read(Vec<u8>),
+}
+
+impl Deref for InputFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            InputFile::Mapped(map) => map,
+            InputFile::Read(buf) => buf,
+        }
+    }
+}
+
+/// Maps the file at `path` so large archives are paged in as they're parsed instead of
+/// read whole up front, falling back to reading it for files that can't be mapped.
+fn open_input(path: &Path) -> std::io::Result<InputFile> {
+    let file = File::open(path)?;
+
+    // Safety: the mapping is only read from, and like any tool reading a file in place we
+    // rely on other processes not truncating it while it's being extracted
+    match unsafe { Mmap::map(&file) } {
+        Ok(map) => Ok(InputFile::Mapped(map)),
+        Err(_) => read_file(path).map(InputFile::Read),
+    }
 }
 
-fn pause() {
+/// Folder the file at `path` is extracted into: next to it, or inside `--output-dir`.
+fn storage_folder(path: &Path, opt: &Run) -> PathBuf {
+    match &opt.output_dir {
+        Some(output_dir) => output_dir.join(path.file_stem().unwrap_or_default()),
+        None => path.with_extension(""),
+    }
+}
+
+fn extract_file(path: &Path, file_buf: &[u8], opt: &Run) -> AResult<()> {
+    let storage_folder = storage_folder(path, opt);
+    confirm_overwrite(&storage_folder, opt)?;
+
+    // only a folder this run created is safe to delete again
+    let created_folder = !storage_folder.exists();
+
+    let fs = disk::Disk(opt);
+    let res = match file_kind(path, file_buf) {
+        Some(FileKind::Pac) => handle_pac(path, file_buf, storage_folder.clone(), opt, &fs),
+        Some(FileKind::Hip) => handle_hip(file_buf, storage_folder.clone(), opt, &fs),
+        Some(FileKind::Hpl) => handle_hpl(file_buf, storage_folder.clone(), opt, &fs),
+        None => Err(anyhow::anyhow!(
+            "File either has no extension or is unrecognized"
+        )),
+    };
+
+    if res.is_err() && opt.clean_on_error && created_folder && storage_folder.exists() {
+        if let Err(e) = std::fs::remove_dir_all(&storage_folder) {
+            log(
+                opt,
+                &format!(
+                    "Failed to clean up {} after error: {}",
+                    storage_folder.display(),
+                    e
+                ),
+            );
+        }
+    }
+
+    res
+}
+
+/// What `file_buf` holds going by its magic bytes, or by the extension of `path` when the
+/// magic isn't one unPAC knows, since renamed entries often carry the wrong extension.
+fn file_kind(path: &Path, file_buf: &[u8]) -> Option<FileKind> {
+    FileKind::sniff(file_buf).or_else(|| {
+        path.extension()
+            .and_then(|e| e.to_str())
+            .and_then(FileKind::from_extension)
+    })
+}
+
+/// Prints a message for the user, kept off stdout in server mode and with `--to-stdout`,
+/// where stdout only carries responses or the repacked file.
+fn log(opt: &Run, message: &str) {
+    if opt.server || opt.to_stdout {
+        eprintln!("{}", message);
+    } else {
+        println!("{}", message);
+    }
+}
+
+/// Reports a recoverable problem, or turns it into an error when running with `--strict`.
+fn warn(opt: &Run, message: String) -> AResult<()> {
+    if opt.strict {
+        Err(anyhow::anyhow!(message))
+    } else {
+        log(
+            opt,
+            &format!("{} {}", color::warning(opt, "WARNING:"), message),
+        );
+        Ok(())
+    }
+}
+
+/// Reports the problems the library works around as warnings, with `--strict` turning
+/// them into errors, and previews each PAC before extracting it.
+struct Cli<'a>(&'a Run);
+
+impl Report for Cli<'_> {
+    fn warn(&self, message: String) -> AResult<()> {
+        warn(self.0, message)
+    }
+
+    fn extracting_pac(&self, path: &Path, meta: &PacMeta, folder: &Path) {
+        let opt = self.0;
+
+        // verifying extracts into memory, there's no folder to point at
+        if opt.quiet || opt.verify {
+            return;
+        }
+
+        let total_size: u64 = meta.entries.iter().map(|e| e.size).sum();
+        log(
+            opt,
+            &format!(
+                "{}: {} entries, {} total - extracting to {}/",
+                path.display(),
+                format_count(meta.entries.len()),
+                color::size(opt, format_size(total_size)),
+                folder.display()
+            ),
+        );
+    }
+}
+
+/// Asks before extracting into a folder that already has files in it. Without a terminal
+/// to ask on, `--overwrite` alone decides.
+fn confirm_overwrite(storage_folder: &Path, opt: &Run) -> AResult<()> {
+    if opt.overwrite || !storage_folder.is_dir() {
+        return Ok(());
+    }
+
+    let existing = std::fs::read_dir(storage_folder)?.count();
+    if existing == 0 {
+        return Ok(());
+    }
+
+    let declined = || {
+        anyhow::anyhow!(
+            "{} already contains {} files, pass --overwrite to replace them",
+            storage_folder.display(),
+            existing
+        )
+    };
+
+    // stdin carries commands in server mode, so there is nobody to ask
+    if opt.server || !(std::io::stdin().is_terminal() && std::io::stdout().is_terminal()) {
+        return Err(declined());
+    }
+
+    let _lock = PROMPT_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+
+    print!(
+        "{} already contains {} files, overwrite them? [y/N] ",
+        storage_folder.display(),
+        existing
+    );
+    std::io::stdout().flush()?;
+
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+
+    match answer.trim().to_lowercase().as_str() {
+        "y" | "yes" => Ok(()),
+        _ => Err(declined()),
+    }
+}
+
+/// Waits for enter so a console window opened by dropping files on the exe stays open,
+/// unless there is no terminal to press it in.
+fn pause(opt: &Run) {
+    if opt.no_pause || !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+        return;
+    }
+
     println!("Press enter to exit...");
     std::io::stdin().read(&mut []).unwrap();
 }
 
-use serde::{Deserialize, Serialize};
-
-#[derive(Serialize, Deserialize)]
-enum MetaKind {
-    Pac(BBCFPac),
-    Hip(BBCFHip),
-    Hpl(BBCFHpl),
-}
-
-fn repack_dir(path: PathBuf) -> AResult<()> {
-    let mut meta_reader = BufReader::new(File::open(path.join(META_FILENAME))?);
-
-    let meta: MetaKind = serde_json::from_reader(&mut meta_reader)?;
-
-    match meta {
-        MetaKind::Pac(mut pac) => {
-            pac.files = pac
-                .files
-                .into_iter()
-                .filter_map(|mut entry| {
-                    let mut contents = Vec::new();
-                    if File::open(path.join(&entry.name))
-                        .and_then(|mut f| f.read_to_end(&mut contents))
-                        .is_ok()
-                    {
-                        entry.contents = contents;
-                        Some(entry)
-                    } else {
-                        println!("Failed to read {}! Excluding from PAC file", entry.name);
-                        None
-                    }
-                })
-                .collect::<Vec<BBCFPacEntry>>();
+fn repack_dir(path: &Path, opt: &Run, fs: &impl FileSystem) -> AResult<()> {
+    let options = opt.repack_options();
 
-            let compressed = pac.to_bytes_compressed();
+    if find_meta(path, fs).is_none() && fs.is_file(&path.join(numbered::INDEX_FILENAME)) {
+        let (meta, files) = numbered::read_numbered(path, opt, fs)?;
+        let bytes = folder::rebuild_pac(path, &meta, &files, &options, &Cli(opt))?;
+        return write_repacked_file(path, bytes, "pac", opt, fs);
+    }
+
+    // any folder passed in gets repacked, so this is usually a mix-up rather than a lost meta
+    if find_meta(path, fs).is_none() {
+        return Err(anyhow::anyhow!(
+            "Folder {} has no meta.json, did you mean to extract a file instead, or is this not an unPAC extraction folder?",
+            path.display()
+        ));
+    }
 
-            write_repacked_file(&path, compressed, "pac")?;
+    match read_meta(path, fs)? {
+        MetaKind::Pac(meta) if opt.stream => {
+            let meta = folder::prepare_pac_meta(path, meta, &options, fs, &Cli(opt))?;
+            repack_pac_streaming(path, meta, opt)
         }
-        MetaKind::Hpl(mut hpl) => {
-            let palette: Vec<RGBAColor> = image::open(path.join("palette.png"))?
-                .pixels()
-                .map(|(_, _, c)| {
-                    let color = c.0;
-                    RGBAColor {
-                        red: color[0] as u8,
-                        green: color[1] as u8,
-                        blue: color[2] as u8,
-                        alpha: color[3] as u8,
-                    }
-                })
-                .collect();
+        meta => {
+            let extension = meta.file_kind().extension();
+            let bytes = folder::repack_meta(path, meta, &options, fs, &Cli(opt))?;
+            write_repacked_file(path, bytes, extension, opt, fs)
+        }
+    }
+}
+
+/// Repacks the PAC in `path` without holding its entries in memory, copying each one
+/// straight from its file into the output.
+///
+/// The compressor needs the whole archive at once, so the result is written uncompressed.
+fn repack_pac_streaming(path: &Path, mut meta: PacMeta, opt: &Run) -> AResult<()> {
+    if meta.entries.iter().any(|e| e.text.is_some()) {
+        return Err(anyhow::anyhow!(
+            "{} has text entries extracted with --text-entries, which --stream can't convert back",
+            path.display()
+        ));
+    }
+
+    if meta.entries.iter().any(|e| e.nested.is_some()) {
+        return Err(anyhow::anyhow!(
+            "{} has nested PACs extracted with --recursive, which --stream can't rebuild",
+            path.display()
+        ));
+    }
 
-            hpl.palette = palette;
+    let shares_data = meta.entries.iter().any(|e| e.shares_data_with.is_some());
+    if meta.dedup_data || meta.overlapping_data || shares_data {
+        warn(
+            opt,
+            format!(
+                "{} is repacked with --stream, which stores entries that shared data separately",
+                path.display()
+            ),
+        )?;
+    }
 
-            let bytes = hpl.to_bytes();
-            write_repacked_file(&path, bytes, "hpl")?;
+    let mut entries = Vec::with_capacity(meta.entries.len());
+    let mut sources = Vec::with_capacity(meta.entries.len());
+    for entry in std::mem::take(&mut meta.entries) {
+        let entry_path = folder::entry_source(path, &meta, &entry)?;
+
+        if entry_path.is_file() {
+            sources.push(entry_path);
+            entries.push(entry);
+        } else {
+            warn(
+                opt,
+                format!("Failed to read {}! Excluding from PAC file", entry.name),
+            )?;
         }
-        MetaKind::Hip(mut hip) => {
-            hip.image = match hip.image {
-                BBCFHipImage::Indexed {
-                    width: _,
-                    height: _,
-                    data: _,
-                } => {
-                    let image = image::open(path.join("image.png"))?;
-                    let palette = image::open(path.join("palette.png"))?;
-
-                    let (width, height) = image.dimensions();
-
-                    let palette: Vec<RGBAColor> = palette
-                        .pixels()
-                        .map(|(_, _, c)| {
-                            let color = c.0;
-                            RGBAColor {
-                                red: color[0] as u8,
-                                green: color[1] as u8,
-                                blue: color[2] as u8,
-                                alpha: color[3] as u8,
-                            }
-                        })
-                        .collect();
-
-                    let image = image.to_luma8().to_vec();
-                    BBCFHipImage::Indexed {
-                        width,
-                        height,
-                        data: IndexedImage { palette, image },
-                    }
-                }
-                BBCFHipImage::Raw {
-                    width: _,
-                    height: _,
-                    data: _,
-                } => {
-                    let image = image::open(path.join("image.png"))?;
-
-                    let (width, height) = image.dimensions();
-
-                    let image: Vec<RGBAColor> = image
-                        .pixels()
-                        .map(|(_, _, c)| {
-                            let color = c.0;
-                            RGBAColor {
-                                red: color[0] as u8,
-                                green: color[1] as u8,
-                                blue: color[2] as u8,
-                                alpha: color[3] as u8,
-                            }
-                        })
-                        .collect();
-
-                    BBCFHipImage::Raw {
-                        width,
-                        height,
-                        data: image,
-                    }
-                }
-                BBCFHipImage::Luma {
-                    width: _,
-                    height: _,
-                    data: _,
-                } => {
-                    let image = image::open(path.join("image.png"))?;
-                    let (width, height) = image.dimensions();
-                    
-                    BBCFHipImage::Luma {
-                        width,
-                        height,
-                        data: image.to_luma16().to_vec(),
-                    }
-                }
-            };
+    }
+    meta.entries = entries;
+    folder::assign_file_ids(&mut meta, &opt.repack_options());
+
+    let write_path = repacked_path(path, "pac", opt, &disk::Disk(opt))?;
+    let mut out = std::io::BufWriter::new(
+        File::create(&write_path).with_context(|| format!("Writing {}", write_path.display()))?,
+    );
+
+    let sizes = sources
+        .iter()
+        .map(|source| std::fs::metadata(source).map(|m| m.len() as usize))
+        .collect::<std::io::Result<Vec<usize>>>()
+        .context("Reading entry sizes")?;
+
+    pac::write_pac_streaming(&meta, &sizes, |index| File::open(&sources[index]), &mut out)
+        .and_then(|_| out.flush())
+        .with_context(|| format!("Writing {}", write_path.display()))
+}
 
-            let bytes = hip.to_bytes();
-            write_repacked_file(&path, bytes, "hip")?;
+/// Where the folder at `path` is repacked to, after making sure writing there is safe.
+fn repacked_path(
+    path: &Path,
+    extension: &str,
+    opt: &Run,
+    fs: &impl FileSystem,
+) -> AResult<PathBuf> {
+    let write_path = match &opt.output_dir {
+        Some(output_dir) => {
+            fs.create_dir_all(output_dir)?;
+            output_dir
+                .join(path.file_name().unwrap_or_default())
+                .with_extension(extension)
         }
+        None => path.with_extension(extension),
+    };
+    ensure_outside_source(path, &write_path, fs)?;
+
+    if fs.is_file(&write_path) {
+        let shown = match &opt.output_dir {
+            Some(_) => write_path.display().to_string(),
+            None => write_path
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+        };
+        warn(opt, format!("{} is being overwritten!", shown))?;
     }
 
-    Ok(())
+    Ok(write_path)
 }
 
 fn write_repacked_file(
-    path: &PathBuf,
+    path: &Path,
     bytes: Vec<u8>,
     extension: &str,
+    opt: &Run,
+    fs: &impl FileSystem,
 ) -> Result<(), anyhow::Error> {
-    let write_path = path.with_extension(extension);
-    if write_path.exists() {
-        println!(
-            "{} is being overwritten!",
-            write_path.file_name().unwrap().to_string_lossy()
-        )
+    if opt.to_stdout {
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&bytes).context("Writing to stdout")?;
+        return stdout.flush().context("Writing to stdout");
     }
-    File::create(write_path)?.write_all(&bytes)?;
+
+    let write_path = repacked_path(path, extension, opt, fs)?;
+
+    fs.write(&write_path, &bytes)
+        .with_context(|| format!("Writing {}", write_path.display()))?;
     Ok(())
 }
 
-fn handle_pac(input: Vec<u8>, storage_folder: PathBuf) -> AResult<()> {
-    use arcsys::bbcf::pac::*;
-
-    let mut pac = BBCFPac::parse(&input)?;
+/// Refuses to write a repacked file anywhere inside the folder it is being built from,
+/// otherwise the output could clobber files that are still being read.
+fn ensure_outside_source(
+    source_folder: &Path,
+    write_path: &Path,
+    fs: &impl FileSource,
+) -> AResult<()> {
+    let source_folder = fs.canonicalize(source_folder)?;
+
+    let write_parent = match write_path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => fs.canonicalize(parent)?,
+        _ => std::env::current_dir()?,
+    };
+
+    if write_parent.starts_with(&source_folder) {
+        return Err(anyhow::anyhow!(
+            "Refusing to write {} inside the folder it is being repacked from ({})",
+            write_path.display(),
+            source_folder.display()
+        ));
+    }
 
-    std::fs::create_dir_all(&storage_folder)?;
+    Ok(())
+}
 
-    for i in &mut pac.files {
-        let mut content_file = File::create(storage_folder.join(&i.name))?;
-        content_file.write_all(&mut i.contents)?;
+/// Renames the entry `old` to `new` inside the PAC at `path`, rewriting the archive.
+///
+/// The archive is read with the same options as an extraction and rebuilt from its meta,
+/// so everything but the name and whatever a wider name field moves stays as it was. The
+/// result is written next to the archive and then moved over it, so a failed rename leaves
+/// the original untouched.
+fn rename_entry(path: &Path, old: &str, new: &str, opt: &Run) -> AResult<()> {
+    let input = std::fs::read(path).context("Reading file")?;
+    let bytes = renamed_pac(path, &input, old, new, opt)?;
+
+    let temp_path = path.with_extension("pac.renaming");
+    std::fs::write(&temp_path, &bytes)
+        .with_context(|| format!("Writing {}", temp_path.display()))?;
+    if let Err(e) = std::fs::rename(&temp_path, path) {
+        let _ = std::fs::remove_file(&temp_path);
+        return Err(e).with_context(|| format!("Replacing {}", path.display()));
     }
 
-    let meta_file = File::create(storage_folder.join(META_FILENAME))?;
-    let mut serializer = serde_json::Serializer::new(meta_file);
-
-    let meta = MetaKind::Pac(pac);
-    meta.serialize(&mut serializer)?;
+    log(
+        opt,
+        &format!("{}: renamed {} to {}", path.display(), old, new),
+    );
 
     Ok(())
 }
 
-fn handle_hpl(input: Vec<u8>, storage_folder: PathBuf) -> AResult<()> {
-    use arcsys::bbcf::hpl::*;
+/// The PAC in `input` with its entry `old` renamed to `new`. A name longer than the name
+/// field widens the field, moving the data region out as far as the table grows.
+fn renamed_pac(path: &Path, input: &[u8], old: &str, new: &str, opt: &Run) -> AResult<Vec<u8>> {
+    if new.is_empty() || new.contains('\0') || new.contains(&['/', '\\'][..]) {
+        return Err(anyhow::anyhow!("`{}` is not a valid entry name", new));
+    }
 
-    let mut hpl = BBCFHpl::parse(&input)?;
+    let plain = plain_pac_bytes(input).context("Decompressing PAC")?;
+    let mut pac = pac::parse_view_with(&plain, &opt.parse_options()).context("Parsing PAC")?;
+    let meta = &mut pac.meta;
+    meta.uncompressed = input.starts_with(pac::HEADER_MAGIC);
 
-    let width = hpl.palette.len();
-    let palette = raw_to_rgba(hpl.palette, width as u32, 1);
+    if meta.entries.iter().any(|entry| entry.name == new) {
+        return Err(anyhow::anyhow!("An entry named {} already exists", new));
+    }
 
-    // replace moved palette with empty vec
-    hpl.palette = Vec::new();
+    let entry = meta
+        .entries
+        .iter_mut()
+        .find(|entry| entry.name == old)
+        .ok_or_else(|| anyhow::anyhow!("No entry named {}", old))?;
+    entry.name = new.to_string();
+    entry.raw_name = None;
+
+    let files: Vec<NamedFile> = meta
+        .entries
+        .iter()
+        .zip(&pac.contents)
+        .map(|(entry, contents)| NamedFile {
+            name: entry.name.clone(),
+            contents: contents.to_vec(),
+        })
+        .collect();
 
-    let hpl = MetaKind::Hpl(hpl);
+    folder::rebuild_pac(path, meta, &files, &opt.repack_options(), &Cli(opt))
+}
 
-    std::fs::create_dir_all(&storage_folder)?;
+/// Extracts the PAC in `input` into `storage_folder`.
+fn handle_pac(
+    path: &Path,
+    input: &[u8],
+    storage_folder: PathBuf,
+    opt: &Run,
+    fs: &impl FileSystem,
+) -> AResult<()> {
+    let options = opt.extract_options();
+
+    if !opt.numbered {
+        return folder::extract_pac_with(path, input, &storage_folder, &options, fs, &Cli(opt));
+    }
 
-    palette.save_with_format(storage_folder.join("palette.png"), image::ImageFormat::Png)?;
+    let plain = plain_pac_bytes(input).context("Decompressing PAC")?;
+    let pac = folder::parse_pac(path, input, &plain, &options.parse, &Cli(opt))?;
+    Cli(opt).extracting_pac(path, &pac.meta, &storage_folder);
 
-    let meta_file = File::create(storage_folder.join(META_FILENAME))?;
-    let mut serializer = serde_json::Serializer::new(meta_file);
+    fs.create_dir_all(&storage_folder)?;
 
-    hpl.serialize(&mut serializer)?;
+    if let (Some(min_free_space), false) = (options.min_free_space, opt.meta_only) {
+        let total_size: u64 = pac.meta.entries.iter().map(|e| e.size).sum();
+        folder::ensure_free_space(&storage_folder, total_size, min_free_space, fs)?;
+    }
 
-    Ok(())
+    numbered::write_numbered(&pac, &storage_folder, opt, fs)
 }
 
-fn handle_hip(input: Vec<u8>, storage_folder: PathBuf) -> AResult<()> {
-    use arcsys::bbcf::hip::*;
+/// Formats `count` with thousands separators, e.g. `1,842`.
+fn format_count(count: usize) -> String {
+    let digits = count.to_string();
+    let mut formatted = String::with_capacity(digits.len() + digits.len() / 3);
 
-    let hip = BBCFHip::parse(&input)?;
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            formatted.push(',');
+        }
+        formatted.push(digit);
+    }
 
-    let image = hip_to_image(hip.image.clone());
+    formatted
+}
 
-    std::fs::create_dir_all(&storage_folder)?;
+/// Creates (or truncates) an extracted file. An existing symlink at `path` is written
+/// through rather than replaced, and on Unix the file gets the `--mode` permissions.
+#[cfg_attr(not(unix), allow(unused_variables))]
+fn create_output(path: &Path, opt: &Run) -> std::io::Result<File> {
+    let file = File::create(path)?;
 
-    if let BBCFHipImage::Indexed {
-        width: _,
-        height: _,
-        data,
-    } = &hip.image
+    #[cfg(unix)]
     {
-        let palette = palette_to_image(&data.palette);
-        palette.save_with_format(storage_folder.join("palette.png"), image::ImageFormat::Png)?;
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(opt.mode))?;
     }
 
-    image.save_with_format(storage_folder.join("image.png"), image::ImageFormat::Png)?;
-    let meta = MetaKind::Hip(hip);
-
-    let meta_file = File::create(storage_folder.join(META_FILENAME))?;
-
-    let mut serializer = serde_json::Serializer::new(meta_file);
-
-    meta.serialize(&mut serializer)?;
+    Ok(file)
+}
 
-    Ok(())
+fn handle_hpl(
+    input: &[u8],
+    storage_folder: PathBuf,
+    opt: &Run,
+    fs: &impl FileSystem,
+) -> AResult<()> {
+    folder::extract_hpl_with(
+        input,
+        &storage_folder,
+        &opt.extract_options(),
+        fs,
+        &Cli(opt),
+    )
 }
 
-fn hip_to_image(hip: BBCFHipImage) -> DynamicImage {
-    type Gray16Image = image::ImageBuffer<image::Luma<u16>, Vec<u16>>;
+fn handle_hip(
+    input: &[u8],
+    storage_folder: PathBuf,
+    opt: &Run,
+    fs: &impl FileSystem,
+) -> AResult<()> {
+    folder::extract_hip_with(input, &storage_folder, &opt.extract_options(), fs)?;
+
+    match &opt.palette {
+        Some(palette_path) if !opt.meta_only => {
+            let hip = BBCFHip::parse(input).context("Parsing HIP")?;
+            write_palette_preview(&hip.image, palette_path, &storage_folder, opt, fs)
+        }
+        _ => Ok(()),
+    }
+}
 
-    match hip {
+/// Renders an indexed `image` with the palette of the HPL at `palette_path` into
+/// preview.png. Only image.png and palette.png are read back on repack, so the external
+/// palette never ends up in the rebuilt HIP.
+fn write_palette_preview(
+    image: &BBCFHipImage,
+    palette_path: &Path,
+    storage_folder: &Path,
+    opt: &Run,
+    fs: &impl FileSink,
+) -> AResult<()> {
+    let (width, height, indices) = match image {
         BBCFHipImage::Indexed {
             width,
             height,
             data,
-        } => DynamicImage::ImageLuma8(indexed_to_luma(data.image, width, height)),
-        BBCFHipImage::Raw {
-            width,
-            height,
-            data,
-        } => DynamicImage::ImageRgba8(raw_to_rgba(data, width, height)),
-        BBCFHipImage::Luma {
-            width,
-            height,
-            data,
-        } => DynamicImage::ImageLuma16(Gray16Image::from_raw(width, height, data).unwrap()),
-    }
+        } => (*width, *height, &data.image),
+        _ => {
+            return warn(
+                opt,
+                format!(
+                    "{} is not an indexed image, ignoring --palette",
+                    storage_folder.display()
+                ),
+            )
+        }
+    };
+
+    let bytes = read_file(palette_path)
+        .with_context(|| format!("Reading palette {}", palette_path.display()))?;
+    let hpl = BBCFHpl::parse(&bytes)
+        .with_context(|| format!("Parsing palette {}", palette_path.display()))?;
+
+    let preview = indexed_to_rgba(indices, &hpl.palette, width, height)
+        .with_context(|| format!("Rendering with palette {}", palette_path.display()))?;
+
+    save_png(
+        &DynamicImage::ImageRgba8(preview),
+        &storage_folder.join("preview.png"),
+        fs,
+    )
+    .context("Writing preview.png")
 }
 
-fn raw_to_rgba(raw: Vec<RGBAColor>, width: u32, height: u32) -> RgbaImage {
-    let pixels: Vec<u8> = raw.into_iter().flat_map(|c| c.to_rgba_slice()).collect();
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    RgbaImage::from_vec(width, height, pixels).unwrap()
-}
-
-fn indexed_to_luma(pixels: Vec<u8>, width: u32, height: u32) -> GrayImage {
-    GrayImage::from_vec(width, height, pixels).unwrap()
-}
+    const TWO_ENTRIES: &[u8] = include_bytes!("../fuzz/corpus/parse/two_entries.pac");
 
-fn palette_to_image(palette: &Vec<RGBAColor>) -> DynamicImage {
-    let width = palette.len();
-    let pixels: Vec<u8> = palette
-        .into_iter()
-        .flat_map(|c| c.to_rgba_slice())
-        .collect();
+    #[test]
+    fn renames_to_longer_name() {
+        let opt = Run::from_iter(&["unpac", "--quiet"]);
+        let new = "a_name_far_too_long_for_the_original_field.bin";
 
-    DynamicImage::ImageRgba8(RgbaImage::from_vec(width as u32, 1, pixels).unwrap())
+        let bytes = renamed_pac(
+            Path::new("two_entries.pac"),
+            TWO_ENTRIES,
+            "b.bin",
+            new,
+            &opt,
+        )
+        .unwrap();
+        let original = pac::parse(TWO_ENTRIES).unwrap();
+        let renamed = pac::parse(&bytes).unwrap();
+
+        assert!(renamed.meta.string_size as usize > new.len());
+        assert!(renamed.meta.data_start > original.meta.data_start);
+        assert_eq!(renamed.meta.entries[0].name, "a.hip");
+        assert_eq!(renamed.meta.entries[1].name, new);
+        for (renamed, original) in renamed.files.iter().zip(&original.files) {
+            assert_eq!(renamed.contents, original.contents);
+        }
+    }
 }
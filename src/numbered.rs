@@ -0,0 +1,273 @@
+//! Extraction into numbered `00000.bin` files plus an `index.csv` mapping each number back
+//! to the entry's name, id and size, for pipelines that don't read meta.json and to avoid
+//! putting entry names in file names at all. The index also records whether each entry was
+//! written, so a folder extracted with --only-images, --only-data or --meta-only isn't
+//! repacked into a shorter PAC by accident.
+
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result as AResult};
+
+use crate::{apply_header_overrides, is_selected, warn, Run};
+use unpac::pac::{self, NamedFile, PacMeta, PacMetaEntry, PacView};
+use unpac::vfs::{FileSink, FileSource};
+
+pub const INDEX_FILENAME: &str = "index.csv";
+
+const INDEX_HEADER: &str = "index,name,file_id,size,extracted";
+
+fn entry_path(folder: &Path, index: usize) -> PathBuf {
+    folder.join(format!("{:05}.bin", index))
+}
+
+/// Writes every entry of `pac` into `folder` under its index, along with `index.csv`.
+pub fn write_numbered(pac: &PacView, folder: &Path, opt: &Run, fs: &impl FileSink) -> AResult<()> {
+    let options = opt.extract_options();
+    let mut index_csv = format!("{}\n", INDEX_HEADER);
+
+    for (index, (entry, contents)) in pac.meta.entries.iter().zip(&pac.contents).enumerate() {
+        let extracted = !opt.meta_only && is_selected(contents, &options);
+
+        writeln!(
+            index_csv,
+            "{},{},{},{},{}",
+            index,
+            quote(&entry.name),
+            entry.file_id,
+            entry.size,
+            extracted
+        )?;
+
+        if extracted {
+            fs.write(&entry_path(folder, index), contents)
+                .with_context(|| format!("Writing entry {}", entry.name))?;
+        }
+    }
+
+    fs.write(&folder.join(INDEX_FILENAME), index_csv.as_bytes())
+        .context("Writing index.csv")
+}
+
+/// Reads `index.csv` and the numbered files in `folder` back into the meta and contents of
+/// a PAC. Header fields aren't part of the index, so they get the same defaults as a
+/// regenerated meta.
+///
+/// Fails if an entry wasn't extracted or its file is missing, unless `--allow-partial`
+/// says to leave it out.
+pub fn read_numbered(
+    folder: &Path,
+    opt: &Run,
+    fs: &impl FileSource,
+) -> AResult<(PacMeta, Vec<NamedFile>)> {
+    let index_csv = fs
+        .read(&folder.join(INDEX_FILENAME))
+        .context("Reading index.csv")?;
+    let index_csv = String::from_utf8(index_csv).context("index.csv is not valid UTF-8")?;
+
+    let mut entries = Vec::new();
+    let mut files = Vec::new();
+
+    let records = split_records(&index_csv).context("Parsing index.csv")?;
+
+    for (record_number, fields) in records.iter().enumerate().skip(1) {
+        if fields.len() == 1 && fields[0].trim().is_empty() {
+            continue;
+        }
+
+        let row = parse_row(fields)
+            .with_context(|| format!("Parsing record {} of index.csv", record_number + 1))?;
+
+        if !row.extracted {
+            if opt.allow_partial {
+                continue;
+            }
+
+            return Err(anyhow::anyhow!(
+                "{} was extracted with --only-images, --only-data or --meta-only and is missing {}, pass --allow-partial to repack it anyway",
+                folder.display(),
+                row.name
+            ));
+        }
+
+        let contents = match fs.read(&entry_path(folder, row.index)) {
+            Ok(contents) => contents,
+            Err(e) if !opt.allow_partial => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Reading {}, pass --allow-partial to repack without it",
+                        row.name
+                    )
+                })
+            }
+            Err(_) => {
+                warn(
+                    opt,
+                    format!("Failed to read {}! Excluding from PAC file", row.name),
+                )?;
+                continue;
+            }
+        };
+
+        entries.push(PacMetaEntry {
+            name: row.name.clone(),
+            raw_name: None,
+            file: None,
+            file_id: row.file_id,
+            offset: 0,
+            size: contents.len() as u64,
+            flags: None,
+            shares_data_with: None,
+            hash: None,
+            shard: None,
+            text: None,
+            nested: None,
+        });
+        files.push(NamedFile {
+            name: row.name,
+            contents,
+        });
+    }
+
+    let mut meta = PacMeta {
+        data_align: opt.data_align,
+        name_terminator: opt.name_terminator,
+        dedup_data: opt.dedup_data,
+        entries,
+        ..PacMeta::default()
+    };
+    meta.string_size = pac::string_size(&meta);
+    apply_header_overrides(&mut meta, &opt.repack_options());
+
+    Ok((meta, files))
+}
+
+struct Row {
+    index: usize,
+    name: String,
+    file_id: u32,
+    extracted: bool,
+}
+
+/// Reads a record of `index.csv`. Indexes written before the `extracted` column existed
+/// count every entry as extracted.
+fn parse_row(fields: &[String]) -> AResult<Row> {
+    let (index, name, file_id, extracted) = match fields {
+        [index, name, file_id, _size] => (index, name, file_id, None),
+        [index, name, file_id, _size, extracted] => (index, name, file_id, Some(extracted)),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Expected 5 fields ({}), found {}",
+                INDEX_HEADER,
+                fields.len()
+            ))
+        }
+    };
+
+    Ok(Row {
+        index: index.parse().context("Invalid index")?,
+        name: name.clone(),
+        file_id: file_id.parse().context("Invalid file_id")?,
+        extracted: match extracted {
+            Some(extracted) => extracted.parse().context("Invalid extracted")?,
+            None => true,
+        },
+    })
+}
+
+/// Quotes `field` if it contains anything CSV would otherwise split on.
+fn quote(field: &str) -> String {
+    if field.contains(|c| matches!(c, ',' | '"' | '\n' | '\r')) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Splits `csv` into records of fields. Quoted fields may hold commas, doubled quotes and
+/// line breaks, which is how `quote` writes names containing them.
+fn split_records(csv: &str) -> AResult<Vec<Vec<String>>> {
+    let mut records = Vec::new();
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = csv.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            '\r' if !quoted && chars.peek() == Some(&'\n') => {}
+            '\n' if !quoted => {
+                fields.push(std::mem::take(&mut field));
+                records.push(std::mem::take(&mut fields));
+            }
+            c => field.push(c),
+        }
+    }
+
+    if quoted {
+        return Err(anyhow::anyhow!("Unterminated quoted field"));
+    }
+
+    if !field.is_empty() || !fields.is_empty() {
+        fields.push(field);
+        records.push(fields);
+    }
+
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+    use unpac::vfs::MemoryFs;
+
+    use super::*;
+
+    const ODD_NAME: &str = "line\nbreak \"quoted\", comma.bin";
+
+    fn numbered_folder(args: &[&str]) -> (MemoryFs, PathBuf) {
+        let bytes = pac::Pac::builder()
+            .add_file("plain.bin", 0, b"plain".to_vec())
+            .add_file(ODD_NAME, 1, b"odd".to_vec())
+            .build()
+            .unwrap();
+        let pac = pac::parse_view(&bytes).unwrap();
+
+        let fs = MemoryFs::new();
+        let folder = PathBuf::from("numbered");
+        write_numbered(&pac, &folder, &Run::from_iter(args), &fs).unwrap();
+
+        (fs, folder)
+    }
+
+    #[test]
+    fn reads_back_quoted_names() {
+        let (fs, folder) = numbered_folder(&["unpac", "--quiet"]);
+
+        let (meta, files) =
+            read_numbered(&folder, &Run::from_iter(&["unpac", "--quiet"]), &fs).unwrap();
+
+        assert_eq!(meta.entries[1].name, ODD_NAME);
+        assert_eq!(meta.entries[1].file_id, 1);
+        assert_eq!(files[0].contents, b"plain");
+        assert_eq!(files[1].contents, b"odd");
+    }
+
+    #[test]
+    fn refuses_partial_folder() {
+        let (fs, folder) = numbered_folder(&["unpac", "--quiet", "--meta-only"]);
+
+        assert!(read_numbered(&folder, &Run::from_iter(&["unpac", "--quiet"]), &fs).is_err());
+
+        let allow_partial = Run::from_iter(&["unpac", "--quiet", "--allow-partial"]);
+        let (meta, files) = read_numbered(&folder, &allow_partial, &fs).unwrap();
+        assert!(meta.entries.is_empty());
+        assert!(files.is_empty());
+    }
+}
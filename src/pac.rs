@@ -0,0 +1,1184 @@
+//! An in-tree reader for the uncompressed FPAC container, used where the layout of the
+//! archive itself matters (offsets, header fields) rather than just its contents.
+
+mod builder;
+mod parser;
+mod reader;
+mod target;
+
+pub use builder::{EntryOrder, Pac, PacBuilder};
+pub use parser::{
+    check_total_size, parse, parse_index, parse_index_with, parse_view, parse_view_with,
+    parse_with_progress,
+};
+pub use reader::{parse_reader, parse_reader_with, PacReader};
+pub use target::Target;
+
+use std::collections::hash_map::{Entry, HashMap};
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{self, prelude::*};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{self, AtomicBool};
+
+use serde::{Deserialize, Serialize};
+
+pub const HEADER_MAGIC: &[u8; 4] = b"FPAC";
+pub const HEADER_SIZE: usize = 0x20;
+/// `file_id`, `offset` and `size` which follow the name of every entry
+pub const ENTRY_FIELDS_SIZE: usize = 0xC;
+/// `file_id` followed by 64-bit `offset` and `size`, as stored by wide variants
+pub const WIDE_ENTRY_FIELDS_SIZE: usize = 0x14;
+/// The extra `flags` field some variants store after `size`
+pub const ENTRY_FLAGS_SIZE: usize = 0x4;
+pub const ALIGNMENT: usize = 0x10;
+
+pub struct ParsedPac {
+    pub meta: PacMeta,
+    pub files: Vec<NamedFile>,
+}
+
+impl ParsedPac {
+    /// Writes the archive back out as uncompressed FPAC, laid out as described by `meta`, so
+    /// an unmodified archive comes out byte for byte as it was parsed.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, PacError> {
+        build_pac(&self.meta, &self.files)
+    }
+}
+
+/// A parsed archive whose entry contents still point into the buffer it was parsed from,
+/// in the same order as `meta.entries`.
+pub struct PacView<'a> {
+    pub meta: PacMeta,
+    pub contents: Vec<&'a [u8]>,
+    /// The broken `string_size` found in the header, if `repair` had to replace it
+    pub repaired_from: Option<u32>,
+    /// The entry count found in the header, if `salvage` had to drop entries past the end
+    /// of the archive
+    pub salvaged_from: Option<usize>,
+}
+
+/// A single entry of the entry table as stored, without its contents.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacEntryInfo {
+    pub name: String,
+    /// Original bytes of a name that had to be decoded lossily
+    pub raw_name: Option<Vec<u8>>,
+    pub file_id: u32,
+    /// Offset of the entry's data, relative to the start of the data region
+    pub offset: u64,
+    pub size: u64,
+    /// Extra field stored after `size` by some variants
+    pub flags: Option<u32>,
+}
+
+pub struct NamedFile {
+    pub name: String,
+    pub contents: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PacMeta {
+    pub unknown: u32,
+    pub string_size: u32,
+    /// The 8 bytes after `string_size` that fill out the header, when not all zero
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub header_padding: Option<[u8; 8]>,
+    /// Offset of the data region as found in the original archive, kept on repack whenever
+    /// the entry table still fits in front of it
+    pub data_start: u32,
+    /// Boundary the data region was padded out to beyond the usual alignment, such as the
+    /// 0x800 sectors of disc images
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_align: Option<u32>,
+    /// How names end within their field, needed to write them back the same way
+    #[serde(default, skip_serializing_if = "NameTerminator::is_default")]
+    pub name_terminator: NameTerminator,
+    /// Whether every entry stores a `flags` field after its size
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub entry_flags: bool,
+    /// Whether entry offsets and sizes are stored as 64-bit fields
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub wide_offsets: bool,
+    pub entries: Vec<PacMetaEntry>,
+    /// Whether the data of the entries was laid out in the order of their recorded offsets
+    /// rather than in table order, which repack then reproduces
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub offset_order: bool,
+    /// Whether the entries are stored in per-entry sidecar files instead of `entries`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub split_meta: bool,
+    /// Number of shard folders the entries are stored in, each listing its own entries
+    /// instead of `entries`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shards: Option<u32>,
+    /// Whether the data ranges of some entries partially overlap, which repack reproduces
+    /// from the recorded offsets
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub overlapping_data: bool,
+    /// Whether every entry with the same contents as an earlier one is pointed at the data of
+    /// that entry on repack, on top of the entries recorded as sharing data
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub dedup_data: bool,
+    /// Content-addressed store the entries were extracted into, instead of next to the meta
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cas: Option<PathBuf>,
+    /// Whether the archive was a plain FPAC rather than compressed, which repack keeps
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub uncompressed: bool,
+    /// Whether only some of the entries listed were extracted, so the folder can't rebuild
+    /// the original archive
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub partial: bool,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Decode names that aren't valid UTF-8 lossily instead of failing
+    pub lossy_names: bool,
+    pub name_terminator: NameTerminator,
+    /// Read entries with a `flags` field after their size
+    pub entry_flags: bool,
+    /// Read entry offsets and sizes as 64-bit fields
+    pub wide_offsets: bool,
+    /// Retry with a neighbouring `string_size` when the entry table doesn't read sanely
+    pub repair: bool,
+    /// Keep the entries before the first one whose data runs past the end of a truncated
+    /// archive instead of failing
+    pub salvage: bool,
+}
+
+/// The entry table layouts of every known PAC variant, by name.
+pub fn known_layouts() -> Vec<(&'static str, ParseOptions)> {
+    let layout = |name_terminator, entry_flags, wide_offsets| ParseOptions {
+        name_terminator,
+        entry_flags,
+        wide_offsets,
+        ..ParseOptions::default()
+    };
+
+    vec![
+        ("standard", layout(NameTerminator::Byte(0), false, false)),
+        (
+            "full-width names",
+            layout(NameTerminator::FullWidth, false, false),
+        ),
+        ("entry flags", layout(NameTerminator::Byte(0), true, false)),
+        (
+            "entry flags, full-width names",
+            layout(NameTerminator::FullWidth, true, false),
+        ),
+        (
+            "64-bit offsets",
+            layout(NameTerminator::Byte(0), false, true),
+        ),
+        (
+            "64-bit offsets, entry flags",
+            layout(NameTerminator::Byte(0), true, true),
+        ),
+    ]
+}
+
+/// Marks where a name ends inside its fixed-size field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NameTerminator {
+    /// The name ends at the first occurrence of this byte, which also pads the rest of the field
+    Byte(u8),
+    /// The name fills the whole field
+    FullWidth,
+}
+
+impl NameTerminator {
+    pub fn is_default(&self) -> bool {
+        *self == NameTerminator::default()
+    }
+
+    /// Bytes needed after a name on top of the name itself.
+    pub fn size(&self) -> usize {
+        match self {
+            NameTerminator::Byte(_) => 1,
+            NameTerminator::FullWidth => 0,
+        }
+    }
+
+    /// Byte the rest of a name field is filled with.
+    pub fn padding(&self) -> u8 {
+        match self {
+            NameTerminator::Byte(b) => *b,
+            NameTerminator::FullWidth => 0,
+        }
+    }
+}
+
+impl Default for NameTerminator {
+    fn default() -> Self {
+        NameTerminator::Byte(0)
+    }
+}
+
+impl FromStr for NameTerminator {
+    type Err = anyhow::Error;
+
+    /// Accepts `none` for full-width names, a byte value in decimal or `0x` hex, or a
+    /// single ASCII character.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "none" {
+            return Ok(NameTerminator::FullWidth);
+        }
+
+        let byte = match s.strip_prefix("0x") {
+            Some(hex) => u8::from_str_radix(hex, 16).ok(),
+            None => s.parse().ok(),
+        };
+
+        match byte {
+            Some(b) => Ok(NameTerminator::Byte(b)),
+            None if s.len() == 1 && s.is_ascii() => Ok(NameTerminator::Byte(s.as_bytes()[0])),
+            None => Err(anyhow::anyhow!(
+                "Expected `none`, a byte value or a single character as terminator, got {}",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PacMetaEntry {
+    pub name: String,
+    /// Original bytes of a name that had to be decoded lossily
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub raw_name: Option<Vec<u8>>,
+    /// Name the entry is extracted under when `name` can't be used as is, because an earlier
+    /// entry already has it or it isn't a safe file name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    pub file_id: u32,
+    /// Offset of the entry's data, relative to `data_start`
+    pub offset: u64,
+    pub size: u64,
+    /// Extra per-entry field stored after `size` by some variants
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub flags: Option<u32>,
+    /// Index of the earlier entry whose data this one pointed at in the original archive,
+    /// which repack points it at again as long as both still have the same contents
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shares_data_with: Option<usize>,
+    /// SHA-256 of the entry's contents, naming its file when extracted into a store
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Shard folder the entry was extracted into
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shard: Option<u32>,
+    /// Original encoding of a text entry that was extracted as UTF-8
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text: Option<TextForm>,
+    /// Form of an inner archive that was extracted into its own folder
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub nested: Option<NestedPac>,
+}
+
+/// How an entry that is itself an archive was stored in its parent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NestedPac {
+    Plain,
+    /// Compressed like the top-level `.pac` files
+    Compressed,
+}
+
+/// How a text entry was stored before being extracted as UTF-8 with LF line endings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TextForm {
+    /// Name of the encoding, such as `Shift_JIS`
+    pub encoding: String,
+    /// Whether lines ended in CRLF
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub crlf: bool,
+}
+
+#[derive(Debug)]
+pub enum PacError {
+    InvalidMagic,
+    /// The archive of `len` bytes ends before its header does
+    TruncatedHeader {
+        len: u64,
+    },
+    /// The header puts the data region at `data_start`, past the end of the `len` bytes
+    DataStartOutOfBounds {
+        data_start: u32,
+        len: u64,
+    },
+    /// The entry at `index` is truncated or has an invalid name
+    FileEntry(usize),
+    /// The data of the entry at `index`, `size` bytes from file offset `offset`, runs past
+    /// the end of the `len` bytes
+    EntryExtendsPastEof {
+        index: usize,
+        offset: u64,
+        size: u64,
+        len: u64,
+    },
+    TotalSize {
+        header: u32,
+        actual: usize,
+    },
+    /// The `field` of the entry at `index` is `value`, more than its 32-bit field can hold
+    EntryFieldOverflow {
+        index: usize,
+        field: &'static str,
+        value: u64,
+    },
+    /// The header `field` is `value`, more than its 32-bit field can hold
+    HeaderFieldOverflow {
+        field: &'static str,
+        value: u64,
+    },
+    /// The cancel flag passed to a `_with_progress` function was set
+    Cancelled,
+    /// Reading the archive from a `PacReader` source failed
+    Io(io::Error),
+}
+
+impl From<io::Error> for PacError {
+    fn from(e: io::Error) -> Self {
+        PacError::Io(e)
+    }
+}
+
+/// Reported by the `_with_progress` functions as they work through an archive.
+#[derive(Debug, Clone, Copy)]
+pub enum ProgressEvent<'a> {
+    /// The entry at `index` of `total` is done
+    Entry {
+        index: usize,
+        total: usize,
+        name: &'a str,
+    },
+    Finished,
+}
+
+/// Checked between entries by the `_with_progress` functions, which give up with
+/// `PacError::Cancelled` once `cancel` is set.
+fn check_cancelled(cancel: &AtomicBool) -> Result<(), PacError> {
+    if cancel.load(atomic::Ordering::Relaxed) {
+        Err(PacError::Cancelled)
+    } else {
+        Ok(())
+    }
+}
+
+impl PacMetaEntry {
+    /// The bytes written to the name field, which may differ from `name` if it was
+    /// decoded lossily.
+    pub fn name_bytes(&self) -> &[u8] {
+        match &self.raw_name {
+            Some(raw) => raw,
+            None => self.name.as_bytes(),
+        }
+    }
+
+    /// Name of the file the entry is extracted to.
+    pub fn file_name(&self) -> &str {
+        self.file.as_deref().unwrap_or(&self.name)
+    }
+}
+
+impl fmt::Display for PacError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacError::InvalidMagic => write!(f, "File does not start with the FPAC magic"),
+            PacError::TruncatedHeader { len } => write!(
+                f,
+                "PAC header needs {:#x} bytes but the file is only {:#x} bytes long",
+                HEADER_SIZE, len
+            ),
+            PacError::DataStartOutOfBounds { data_start, len } => write!(
+                f,
+                "Header puts the data region at {:#x}, past the end of the file at {:#x}",
+                data_start, len
+            ),
+            PacError::FileEntry(index) => {
+                write!(f, "Entry {} is truncated or has an invalid name", index)
+            }
+            PacError::EntryExtendsPastEof {
+                index,
+                offset,
+                size,
+                len,
+            } => write!(
+                f,
+                "Entry {} at {:#x} with size {:#x} runs past the end of the file at {:#x}",
+                index, offset, size, len
+            ),
+            PacError::TotalSize { header, actual } => write!(
+                f,
+                "Header total_size is {:#x} but the archive is {:#x} bytes long",
+                header, actual
+            ),
+            PacError::EntryFieldOverflow {
+                index,
+                field,
+                value,
+            } => write!(
+                f,
+                "Entry {} {} {:#x} doesn't fit in 32 bits, repack with 64-bit offsets \
+                 (--wide-offsets, or wide_offsets in meta.json)",
+                index, field, value
+            ),
+            PacError::HeaderFieldOverflow { field, value } => write!(
+                f,
+                "Header {} {:#x} doesn't fit in its 32-bit field",
+                field, value
+            ),
+            PacError::Cancelled => write!(f, "Cancelled"),
+            PacError::Io(e) => write!(f, "Failed to read archive: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PacError {}
+
+pub fn align(size: usize) -> usize {
+    align_to(size, ALIGNMENT)
+}
+
+/// Rounds `size` up to a multiple of `alignment`, which must be a power of two.
+pub fn align_to(size: usize, alignment: usize) -> usize {
+    (size + alignment - 1) & !(alignment - 1)
+}
+
+/// Infers the alignment of a data region starting at `data_start` when it sits further
+/// out than the entry table ending at `table_end` needs, otherwise `None`.
+pub fn infer_data_align(data_start: u32, table_end: usize) -> Option<u32> {
+    if data_start == 0 || data_start as usize <= align(table_end) {
+        return None;
+    }
+
+    let data_align = 1 << data_start.trailing_zeros();
+    if data_align as usize > ALIGNMENT {
+        Some(data_align)
+    } else {
+        None
+    }
+}
+
+/// Size of a single entry in the entry table, including its trailing padding.
+pub fn entry_size(string_size: u32, entry_flags: bool, wide_offsets: bool) -> usize {
+    align(string_size as usize + fields_size(entry_flags, wide_offsets))
+}
+
+/// Size of the fields following the name of every entry.
+pub fn fields_size(entry_flags: bool, wide_offsets: bool) -> usize {
+    let fields = if wide_offsets {
+        WIDE_ENTRY_FIELDS_SIZE
+    } else {
+        ENTRY_FIELDS_SIZE
+    };
+
+    if entry_flags {
+        fields + ENTRY_FLAGS_SIZE
+    } else {
+        fields
+    }
+}
+
+/// Size of the name field needed to fit every name in `meta` plus its terminator,
+/// reusing the original size when the names still fit.
+pub fn string_size(meta: &PacMeta) -> u32 {
+    let longest = meta
+        .entries
+        .iter()
+        .map(|e| e.name_bytes().len())
+        .max()
+        .unwrap_or(0);
+
+    let needed = longest + meta.name_terminator.size();
+
+    if needed <= meta.string_size as usize {
+        meta.string_size
+    } else {
+        ((needed + 3) & !3) as u32
+    }
+}
+
+/// Serializes the entry at `index` of the entry table, padded to
+/// `entry_size(string_size, meta.entry_flags, meta.wide_offsets)`.
+///
+/// Fails if `offset` or `size` don't fit in 32 bits without `meta.wide_offsets`.
+pub fn to_entry_bytes(
+    index: usize,
+    entry: &PacMetaEntry,
+    offset: u64,
+    size: u64,
+    string_size: u32,
+    meta: &PacMeta,
+) -> Result<Vec<u8>, PacError> {
+    let entry_flags = meta.entry_flags;
+    let entry_size = entry_size(string_size, entry_flags, meta.wide_offsets);
+    let mut bytes = Vec::with_capacity(entry_size);
+
+    bytes.extend_from_slice(entry.name_bytes());
+    bytes.resize(string_size as usize, meta.name_terminator.padding());
+    bytes.extend_from_slice(&entry.file_id.to_le_bytes());
+    if meta.wide_offsets {
+        bytes.extend_from_slice(&offset.to_le_bytes());
+        bytes.extend_from_slice(&size.to_le_bytes());
+    } else {
+        for (field, value) in [("offset", offset), ("size", size)] {
+            let narrow = u32::try_from(value).map_err(|_| PacError::EntryFieldOverflow {
+                index,
+                field,
+                value,
+            })?;
+            bytes.extend_from_slice(&narrow.to_le_bytes());
+        }
+    }
+    if entry_flags {
+        bytes.extend_from_slice(&entry.flags.unwrap_or(0).to_le_bytes());
+    }
+    bytes.resize(entry_size, 0);
+
+    Ok(bytes)
+}
+
+/// Where every part of an archive goes, worked out from the entry sizes alone so the data
+/// itself can be written afterwards.
+///
+/// Entry data is laid out in table order, or in the order of the recorded offsets when
+/// `meta.offset_order` is set. The data region starts on a multiple of `meta.data_align`
+/// when one is set.
+///
+/// Entries marked in `within` as lying inside the data of another entry, given as that
+/// entry's index and the offset into it, take no space of their own. The entry they lie
+/// within grows to cover them.
+pub struct Layout {
+    pub string_size: u32,
+    pub data_start: usize,
+    /// Offset of each entry's data, relative to `data_start`
+    pub offsets: Vec<u64>,
+    pub total_size: usize,
+}
+
+impl Layout {
+    pub fn new(meta: &PacMeta, sizes: &[usize]) -> Layout {
+        Layout::with_shared(meta, sizes, &[])
+    }
+
+    pub fn with_shared(meta: &PacMeta, sizes: &[usize], within: &[Option<(usize, u64)>]) -> Layout {
+        let string_size = string_size(meta);
+        let table_end = HEADER_SIZE
+            + entry_size(string_size, meta.entry_flags, meta.wide_offsets) * meta.entries.len();
+
+        let data_align = meta.data_align.map_or(ALIGNMENT, |a| a as usize);
+
+        // the original data_start is kept even when unaligned, unless data_align asks for a
+        // boundary it isn't on
+        let data_start = if meta.data_start as usize >= table_end
+            && meta.data_align.map_or(true, |a| meta.data_start % a == 0)
+        {
+            meta.data_start as usize
+        } else {
+            align_to(table_end, data_align)
+        };
+
+        let count = meta.entries.len().min(sizes.len());
+
+        let mut order: Vec<usize> = (0..count).collect();
+        if meta.offset_order {
+            order.sort_by_key(|&index| meta.entries[index].offset);
+        }
+
+        let within = |index: usize| within.get(index).copied().flatten();
+
+        // space taken by each entry, including whatever lies within it
+        let mut footprints = sizes[..count].to_vec();
+        for index in 0..count {
+            if let Some((outer, offset)) = within(index) {
+                footprints[outer] = footprints[outer].max(offset as usize + sizes[index]);
+            }
+        }
+
+        let mut offsets = vec![0; count];
+        let mut data_size = 0;
+
+        for &index in &order {
+            if within(index).is_none() {
+                offsets[index] = data_size as u64;
+                data_size = align(data_size + footprints[index]);
+            }
+        }
+
+        for index in order {
+            if let Some((outer, offset)) = within(index) {
+                offsets[index] = offsets[outer] + offset;
+            }
+        }
+
+        Layout {
+            string_size,
+            data_start,
+            offsets,
+            total_size: data_start + data_size,
+        }
+    }
+}
+
+/// Serializes the header and entry table of an archive laid out as `layout`, padded up to
+/// the start of its data region.
+///
+/// `total_size` is written as the full length of the archive: header, entry table and
+/// the aligned data of every entry.
+pub fn table_bytes(meta: &PacMeta, sizes: &[usize], layout: &Layout) -> Result<Vec<u8>, PacError> {
+    let header_field = |field, value: usize| {
+        u32::try_from(value).map_err(|_| PacError::HeaderFieldOverflow {
+            field,
+            value: value as u64,
+        })
+    };
+
+    let mut bytes = Vec::with_capacity(layout.data_start);
+    bytes.extend_from_slice(HEADER_MAGIC);
+    bytes.extend_from_slice(&header_field("data_start", layout.data_start)?.to_le_bytes());
+    bytes.extend_from_slice(&header_field("total_size", layout.total_size)?.to_le_bytes());
+    bytes.extend_from_slice(&(meta.entries.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&meta.unknown.to_le_bytes());
+    bytes.extend_from_slice(&layout.string_size.to_le_bytes());
+    bytes.extend_from_slice(&meta.header_padding.unwrap_or_default());
+
+    for (index, ((entry, &size), &offset)) in meta
+        .entries
+        .iter()
+        .zip(sizes)
+        .zip(&layout.offsets)
+        .enumerate()
+    {
+        bytes.extend(to_entry_bytes(
+            index,
+            entry,
+            offset,
+            size as u64,
+            layout.string_size,
+            meta,
+        )?);
+    }
+
+    bytes.resize(layout.data_start, 0);
+
+    Ok(bytes)
+}
+
+/// Sets the `file` of every entry whose name can't be extracted as is.
+///
+/// Names that could point outside the extraction folder are made safe first, then entries
+/// whose name was already used by an earlier entry get `<name>.1`, `<name>.2` and so on, so
+/// they don't overwrite each other. Names are compared ignoring case, as they would be on
+/// Windows.
+pub fn assign_file_names(entries: &mut [PacMetaEntry]) {
+    let safe_names: Vec<String> = entries.iter().map(|e| safe_file_name(&e.name)).collect();
+    let mut taken: HashSet<String> = safe_names.iter().map(|n| n.to_lowercase()).collect();
+    let mut seen = HashSet::new();
+
+    for (entry, safe_name) in entries.iter_mut().zip(safe_names) {
+        let file = if seen.insert(safe_name.to_lowercase()) {
+            safe_name
+        } else {
+            let file = (1..)
+                .map(|n| format!("{}.{}", safe_name, n))
+                .find(|file| !taken.contains(&file.to_lowercase()))
+                .unwrap();
+            taken.insert(file.to_lowercase());
+            file
+        };
+
+        entry.file = Some(file).filter(|file| *file != entry.name);
+    }
+}
+
+/// `name` as a single plain path component: separators, drive colons and NULs become `_`,
+/// and names that would refer to the folder itself or its parent get a `_` in front.
+fn safe_file_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '\0' => '_',
+            c => c,
+        })
+        .collect();
+
+    match name.as_str() {
+        "" | "." | ".." => format!("_{}", name),
+        _ => name,
+    }
+}
+
+/// Whether `name` can be joined onto a folder without pointing anywhere but inside it.
+pub fn is_safe_file_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+
+    matches!(
+        (components.next(), components.next()),
+        (Some(Component::Normal(_)), None)
+    ) && !name.contains(['/', '\\', ':'])
+}
+
+/// For every file, the index of the first earlier file with the same non-empty contents.
+pub fn shared_data(files: &[NamedFile]) -> Vec<Option<usize>> {
+    let mut first_seen: HashMap<&[u8], usize> = HashMap::new();
+
+    files
+        .iter()
+        .enumerate()
+        .map(|(index, file)| {
+            if file.contents.is_empty() {
+                return None;
+            }
+
+            match first_seen.entry(file.contents.as_slice()) {
+                Entry::Occupied(first) => Some(*first.get()),
+                Entry::Vacant(slot) => {
+                    slot.insert(index);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+/// For every file, the entry its `shares_data_with` points at, if that is an earlier entry
+/// with the same contents.
+pub fn recorded_shared_data(meta: &PacMeta, files: &[NamedFile]) -> Vec<Option<usize>> {
+    (0..files.len())
+        .map(|index| {
+            meta.entries
+                .get(index)
+                .and_then(|entry| entry.shares_data_with)
+                .filter(|&first| {
+                    first < index
+                        && !files[index].contents.is_empty()
+                        && files[first].contents == files[index].contents
+                })
+        })
+        .collect()
+}
+
+/// Groups the entries of `meta` whose recorded data ranges overlap and places every entry
+/// of a group within the one starting first, at the same distance as originally.
+///
+/// A group whose entries changed size or no longer agree on the bytes they share is laid
+/// out normally instead.
+pub fn overlapping_data(meta: &PacMeta, files: &[NamedFile]) -> Vec<Option<(usize, u64)>> {
+    let mut within = vec![None; files.len()];
+
+    let mut by_offset: Vec<usize> = (0..files.len().min(meta.entries.len()))
+        .filter(|&index| meta.entries[index].size > 0)
+        .collect();
+    by_offset.sort_by_key(|&index| meta.entries[index].offset);
+
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_end = 0;
+    for index in by_offset {
+        let entry = &meta.entries[index];
+
+        match groups.last_mut() {
+            Some(group) if entry.offset < group_end => group.push(index),
+            _ => groups.push(vec![index]),
+        }
+        group_end = group_end.max(entry.offset + entry.size);
+    }
+
+    for group in groups.iter().filter(|g| g.len() > 1) {
+        let outer = group[0];
+        let base = meta.entries[outer].offset;
+        let region_size = group
+            .iter()
+            .map(|&index| meta.entries[index].offset + meta.entries[index].size - base)
+            .max()
+            .unwrap_or(0) as usize;
+
+        let mut region: Vec<Option<u8>> = vec![None; region_size];
+        let consistent = group.iter().all(|&index| {
+            let entry = &meta.entries[index];
+            let contents = &files[index].contents;
+            let start = (entry.offset - base) as usize;
+
+            contents.len() as u64 == entry.size
+                && contents
+                    .iter()
+                    .enumerate()
+                    .all(|(i, &b)| *region[start + i].get_or_insert(b) == b)
+        });
+
+        if consistent {
+            for &index in &group[1..] {
+                within[index] = Some((outer, meta.entries[index].offset - base));
+            }
+        }
+    }
+
+    within
+}
+
+/// Builds an uncompressed FPAC archive out of `meta` and the contents of each of its
+/// entries, given in the same order as `meta.entries`.
+///
+/// With `meta.overlapping_data` set, entries whose data overlapped in the original archive
+/// overlap the same way again as long as their contents still agree. Entries with
+/// `shares_data_with` set point at the data of that entry again if their contents still
+/// match, and with `meta.dedup_data` set so does every other entry with the same contents
+/// as an earlier one.
+///
+/// Fails if an offset or size doesn't fit in its field, see `PacError::EntryFieldOverflow`.
+pub fn build_pac(meta: &PacMeta, files: &[NamedFile]) -> Result<Vec<u8>, PacError> {
+    build_pac_inner(meta, files, |_, _| Ok(()))
+}
+
+/// Builds the same archive as `build_pac`, calling `progress` after copying in each entry
+/// and stopping with `PacError::Cancelled` as soon as `cancel` is set.
+///
+/// Nothing is returned on cancellation, so a host never sees a half-built archive.
+pub fn build_pac_with_progress(
+    meta: &PacMeta,
+    files: &[NamedFile],
+    progress: &dyn Fn(ProgressEvent),
+    cancel: &AtomicBool,
+) -> Result<Vec<u8>, PacError> {
+    let total = files.len();
+
+    let bytes = build_pac_inner(meta, files, |index, name| {
+        check_cancelled(cancel)?;
+        progress(ProgressEvent::Entry { index, total, name });
+        Ok(())
+    })?;
+    progress(ProgressEvent::Finished);
+
+    Ok(bytes)
+}
+
+fn build_pac_inner(
+    meta: &PacMeta,
+    files: &[NamedFile],
+    mut on_entry: impl FnMut(usize, &str) -> Result<(), PacError>,
+) -> Result<Vec<u8>, PacError> {
+    let sizes: Vec<usize> = files.iter().map(|f| f.contents.len()).collect();
+
+    let mut within = if meta.overlapping_data {
+        overlapping_data(meta, files)
+    } else {
+        vec![None; files.len()]
+    };
+    let shared = if meta.dedup_data {
+        shared_data(files)
+    } else {
+        recorded_shared_data(meta, files)
+    };
+    let outers: HashSet<usize> = within.iter().flatten().map(|&(outer, _)| outer).collect();
+    for (index, first) in shared.into_iter().enumerate() {
+        // entries already placed within others, or holding others, keep their place
+        let free = within[index].is_none() && !outers.contains(&index);
+
+        if let Some(first) = first.filter(|&first| free && within[first].is_none()) {
+            within[index] = Some((first, 0));
+        }
+    }
+    let layout = Layout::with_shared(meta, &sizes, &within);
+
+    let mut bytes = table_bytes(meta, &sizes, &layout)?;
+    bytes.resize(layout.total_size, 0);
+
+    for (index, (file, &offset)) in files.iter().zip(&layout.offsets).enumerate() {
+        let start = layout.data_start + offset as usize;
+        bytes[start..start + file.contents.len()].copy_from_slice(&file.contents);
+
+        on_entry(index, &file.name)?;
+    }
+
+    Ok(bytes)
+}
+
+/// Writes the same archive as `build_pac` to `out`, given the size of each entry up front
+/// and reading its contents from `open(index)` one at a time, so only a single entry is
+/// ever held in memory.
+pub fn write_pac_streaming<W: Write, R: Read>(
+    meta: &PacMeta,
+    sizes: &[usize],
+    mut open: impl FnMut(usize) -> io::Result<R>,
+    out: &mut W,
+) -> io::Result<()> {
+    let layout = Layout::new(meta, sizes);
+
+    let table = table_bytes(meta, sizes, &layout)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    out.write_all(&table)?;
+
+    let mut order: Vec<usize> = (0..layout.offsets.len()).collect();
+    order.sort_by_key(|&index| layout.offsets[index]);
+
+    let mut written = layout.data_start;
+    for index in order {
+        let start = layout.data_start + layout.offsets[index] as usize;
+        io::copy(&mut io::repeat(0).take((start - written) as u64), out)?;
+
+        let source = open(index)?;
+        let copied = io::copy(&mut source.take(sizes[index] as u64), out)?;
+        if copied != sizes[index] as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} shrank while repacking", meta.entries[index].name),
+            ));
+        }
+
+        written = start + sizes[index];
+    }
+
+    io::copy(
+        &mut io::repeat(0).take((layout.total_size - written) as u64),
+        out,
+    )?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    const TWO_ENTRIES: &[u8] = include_bytes!("../fuzz/corpus/parse/two_entries.pac");
+    const OVERLAPPING: &[u8] = include_bytes!("../fuzz/corpus/parse/overlapping.pac");
+    const EMPTY: &[u8] = include_bytes!("../fuzz/corpus/parse/empty.pac");
+
+    /// `TWO_ENTRIES` with its data region moved `shift` bytes further out.
+    fn with_data_shifted(shift: usize) -> Vec<u8> {
+        let mut bytes = TWO_ENTRIES.to_vec();
+        let data_start = 0x80 + shift;
+        bytes.splice(0x80..0x80, vec![0; shift]);
+
+        let total_size = bytes.len();
+        bytes[0x4..0x8].copy_from_slice(&(data_start as u32).to_le_bytes());
+        bytes[0x8..0xC].copy_from_slice(&(total_size as u32).to_le_bytes());
+
+        bytes
+    }
+
+    fn assert_round_trips(input: &[u8]) -> ParsedPac {
+        let pac = parse(input).unwrap();
+        assert_eq!(pac.to_bytes().unwrap(), input);
+
+        pac
+    }
+
+    #[test]
+    fn keeps_unaligned_data_start() {
+        let input = with_data_shifted(8);
+        let pac = assert_round_trips(&input);
+
+        assert_eq!(pac.meta.data_start, 0x88);
+        assert_eq!(pac.meta.data_align, None);
+        assert_eq!(pac.files[1].contents, b"hello");
+    }
+
+    #[test]
+    fn writes_total_size() {
+        let bytes = Pac::builder()
+            .add_file("a.bin", 0, vec![1; 0x13])
+            .add_file("b.bin", 1, vec![2; 0x5])
+            .build()
+            .unwrap();
+
+        let total_size = u32::from_le_bytes(bytes[0x8..0xC].try_into().unwrap());
+        assert_eq!(total_size as usize, bytes.len());
+        check_total_size(&bytes).unwrap();
+    }
+
+    #[test]
+    fn rejects_wrong_total_size() {
+        let mut bytes = TWO_ENTRIES.to_vec();
+        bytes[0x8..0xC].copy_from_slice(&0xA4u32.to_le_bytes());
+
+        assert!(matches!(
+            check_total_size(&bytes),
+            Err(PacError::TotalSize {
+                header: 0xA4,
+                actual: 0xA0
+            })
+        ));
+    }
+
+    #[test]
+    fn keeps_sector_aligned_data_region() {
+        let input = with_data_shifted(0x780);
+        let pac = assert_round_trips(&input);
+
+        assert_eq!(pac.meta.data_start, 0x800);
+        assert_eq!(pac.meta.data_align, Some(0x800));
+    }
+
+    #[test]
+    fn aligns_data_region_to_data_align() {
+        let bytes = Pac::builder()
+            .data_align(0x800)
+            .add_file("a.bin", 0, vec![1; 0x13])
+            .build()
+            .unwrap();
+
+        let data_start = u32::from_le_bytes(bytes[0x4..0x8].try_into().unwrap());
+        assert_eq!(data_start, 0x800);
+        assert_eq!(&bytes[0x800..0x813], &[1; 0x13][..]);
+        assert_round_trips(&bytes);
+    }
+
+    #[test]
+    fn keeps_data_out_of_table_order() {
+        // the data of b.bin first, then that of a.hip
+        let mut input = TWO_ENTRIES.to_vec();
+        input[0x44..0x48].copy_from_slice(&0x10u32.to_le_bytes());
+        input[0x74..0x78].copy_from_slice(&0u32.to_le_bytes());
+        let (a, b) = input[0x80..0xA0].split_at_mut(0x10);
+        a.swap_with_slice(b);
+
+        let pac = assert_round_trips(&input);
+
+        assert!(pac.meta.offset_order);
+        assert_eq!(pac.files[0].contents, &TWO_ENTRIES[0x80..0x90]);
+        assert_eq!(pac.files[1].contents, b"hello");
+    }
+
+    #[test]
+    fn round_trips_every_known_layout() {
+        // names of the same length, so they also fill full-width fields exactly
+        let files = vec![
+            NamedFile {
+                name: "abcd.bin".to_string(),
+                contents: vec![1; 0x13],
+            },
+            NamedFile {
+                name: "efgh.bin".to_string(),
+                contents: vec![2; 0x5],
+            },
+        ];
+
+        for (layout, options) in known_layouts() {
+            let meta = PacMeta {
+                name_terminator: options.name_terminator,
+                entry_flags: options.entry_flags,
+                wide_offsets: options.wide_offsets,
+                entries: files
+                    .iter()
+                    .enumerate()
+                    .map(|(index, file)| PacMetaEntry {
+                        name: file.name.clone(),
+                        raw_name: None,
+                        file: None,
+                        file_id: index as u32,
+                        offset: 0,
+                        size: file.contents.len() as u64,
+                        flags: Some(0x100 + index as u32).filter(|_| options.entry_flags),
+                        shares_data_with: None,
+                        hash: None,
+                        shard: None,
+                        text: None,
+                        nested: None,
+                    })
+                    .collect(),
+                ..PacMeta::default()
+            };
+
+            let bytes = build_pac(&meta, &files).unwrap();
+            let view = parse_view_with(&bytes, &options).unwrap();
+
+            for ((entry, contents), (expected, file)) in view
+                .meta
+                .entries
+                .iter()
+                .zip(&view.contents)
+                .zip(meta.entries.iter().zip(&files))
+            {
+                assert_eq!(entry.name, expected.name, "{}", layout);
+                assert_eq!(entry.flags, expected.flags, "{}", layout);
+                assert_eq!(*contents, file.contents.as_slice(), "{}", layout);
+            }
+            assert_eq!(build_pac(&view.meta, &files).unwrap(), bytes, "{}", layout);
+        }
+    }
+
+    #[test]
+    fn extracts_overlapping_entries() {
+        let pac = assert_round_trips(OVERLAPPING);
+        let data = &OVERLAPPING[0xE0..];
+
+        assert!(pac.meta.overlapping_data);
+        assert_eq!(pac.files[0].contents, &data[0x0..0x10]);
+        assert_eq!(pac.files[1].contents, &data[0x0..0x8]);
+        assert_eq!(pac.files[2].contents, &data[0x8..0x10]);
+        assert_eq!(pac.files[3].contents, &data[0x4..0xC]);
+        assert_eq!(
+            overlapping_data(&pac.meta, &pac.files),
+            vec![None, Some((0, 0)), Some((0, 8)), Some((0, 4))]
+        );
+    }
+
+    #[test]
+    fn round_trips_fixtures() {
+        let pac = assert_round_trips(TWO_ENTRIES);
+        assert_eq!(pac.meta.entries[0].name, "a.hip");
+        assert_eq!(pac.meta.entries[1].name, "b.bin");
+        assert_eq!(pac.files[1].contents, b"hello");
+
+        let pac = assert_round_trips(EMPTY);
+        assert!(pac.meta.entries.is_empty());
+    }
+
+    /// `TWO_ENTRIES` with the 32-bit field at `offset` set to `value`.
+    fn with_field(offset: usize, value: u32) -> Vec<u8> {
+        let mut bytes = TWO_ENTRIES.to_vec();
+        bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+
+        bytes
+    }
+
+    #[test]
+    fn rejects_corrupt_header() {
+        assert!(matches!(parse(b"XXXX"), Err(PacError::InvalidMagic)));
+        assert!(matches!(
+            parse(b"FPAC"),
+            Err(PacError::TruncatedHeader { len: 4 })
+        ));
+        assert!(matches!(
+            parse(&with_field(0x4, 0x100)),
+            Err(PacError::DataStartOutOfBounds {
+                data_start: 0x100,
+                len: 0xA0
+            })
+        ));
+    }
+
+    #[test]
+    fn rejects_corrupt_entry_table() {
+        // a third entry would start at 0x80, running into the data region and past the end
+        assert!(matches!(
+            parse(&with_field(0xC, 3)),
+            Err(PacError::FileEntry(2))
+        ));
+        assert!(matches!(
+            parse(&with_field(0x78, 0x100)),
+            Err(PacError::EntryExtendsPastEof {
+                index: 1,
+                offset: 0x90,
+                size: 0x100,
+                len: 0xA0
+            })
+        ));
+    }
+
+    #[test]
+    fn bbcf_target_keeps_header() {
+        let mut pac = parse(TWO_ENTRIES).unwrap();
+        "bbcf".parse::<Target>().unwrap().apply(&mut pac.meta);
+
+        assert_eq!(pac.to_bytes().unwrap(), TWO_ENTRIES);
+    }
+}
@@ -0,0 +1,128 @@
+use std::cmp::Ordering;
+
+use super::{build_pac, NameTerminator, NamedFile, PacError, PacMeta, PacMetaEntry, Target};
+
+/// Namespace for building archives in code, see [`PacBuilder`].
+pub struct Pac;
+
+impl Pac {
+    pub fn builder() -> PacBuilder {
+        PacBuilder::default()
+    }
+}
+
+/// Builds an uncompressed FPAC archive entry by entry.
+///
+/// `string_size`, entry offsets, alignment and the header fields are all derived when
+/// calling [`build`](PacBuilder::build), so only the contents need to be supplied.
+#[derive(Default)]
+pub struct PacBuilder {
+    meta: PacMeta,
+    files: Vec<NamedFile>,
+    order: EntryOrder,
+}
+
+/// Order entries are written in, both in the entry table and in the data region.
+///
+/// Games usually look entries up by `file_id` or name, and some expect the table to be
+/// sorted by one of them to search it, so an archive whose order doesn't match what the
+/// game expects may fail to find entries even though they're all there.
+#[derive(Debug, Clone, Copy)]
+pub enum EntryOrder {
+    /// The order the entries were added in
+    Insertion,
+    Name,
+    Id,
+    Custom(fn(&PacMetaEntry, &PacMetaEntry) -> Ordering),
+}
+
+impl Default for EntryOrder {
+    fn default() -> Self {
+        EntryOrder::Insertion
+    }
+}
+
+impl EntryOrder {
+    fn compare(&self, a: &PacMetaEntry, b: &PacMetaEntry) -> Ordering {
+        match self {
+            EntryOrder::Insertion => Ordering::Equal,
+            EntryOrder::Name => a.name_bytes().cmp(b.name_bytes()),
+            EntryOrder::Id => a.file_id.cmp(&b.file_id),
+            EntryOrder::Custom(compare) => compare(a, b),
+        }
+    }
+}
+
+impl PacBuilder {
+    pub fn unknown(mut self, unknown: u32) -> Self {
+        self.meta.unknown = unknown;
+        self
+    }
+
+    /// Starts the data region on a multiple of `data_align` bytes, which must be a power of two.
+    pub fn data_align(mut self, data_align: u32) -> Self {
+        self.meta.data_align = Some(data_align);
+        self
+    }
+
+    /// Uses the header values of `target` for everything but the entries.
+    pub fn target(mut self, target: &Target) -> Self {
+        target.apply(&mut self.meta);
+        self
+    }
+
+    pub fn name_terminator(mut self, name_terminator: NameTerminator) -> Self {
+        self.meta.name_terminator = name_terminator;
+        self
+    }
+
+    /// Lays the entries out in `order` instead of the order they were added in. Entries
+    /// that compare equal keep their insertion order.
+    pub fn order(mut self, order: EntryOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    pub fn add_file(
+        mut self,
+        name: impl Into<String>,
+        file_id: u32,
+        contents: impl Into<Vec<u8>>,
+    ) -> Self {
+        let name = name.into();
+        let contents = contents.into();
+
+        self.meta.entries.push(PacMetaEntry {
+            name: name.clone(),
+            raw_name: None,
+            file: None,
+            file_id,
+            offset: 0,
+            size: contents.len() as u64,
+            flags: None,
+            shares_data_with: None,
+            hash: None,
+            shard: None,
+            text: None,
+            nested: None,
+        });
+        self.files.push(NamedFile { name, contents });
+
+        self
+    }
+
+    pub fn build(self) -> Result<Vec<u8>, PacError> {
+        let PacBuilder {
+            mut meta,
+            files,
+            order,
+        } = self;
+
+        let mut pairs: Vec<(PacMetaEntry, NamedFile)> = meta.entries.drain(..).zip(files).collect();
+        pairs.sort_by(|(a, _), (b, _)| order.compare(a, b));
+        let (entries, files): (Vec<_>, Vec<_>) = pairs.into_iter().unzip();
+        meta.entries = entries;
+
+        build_pac(&meta, &files)
+    }
+}
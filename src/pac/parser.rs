@@ -0,0 +1,397 @@
+use std::convert::{TryFrom, TryInto};
+use std::ops::Range;
+use std::sync::atomic::AtomicBool;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use super::{
+    check_cancelled, entry_size, fields_size, infer_data_align, NameTerminator, NamedFile,
+    PacEntryInfo, PacError, PacMeta, PacMetaEntry, PacView, ParseOptions, ParsedPac, ProgressEvent,
+    ALIGNMENT, HEADER_MAGIC, HEADER_SIZE,
+};
+
+pub(super) struct Header {
+    pub(super) data_start: u32,
+    pub(super) total_size: u32,
+    pub(super) file_count: u32,
+    pub(super) unknown: u32,
+    pub(super) string_size: u32,
+    pub(super) padding: [u8; 8],
+}
+
+/// Parses an archive into owned entries.
+pub fn parse(input: &[u8]) -> Result<ParsedPac, PacError> {
+    let view = parse_view(input)?;
+
+    let files = view
+        .meta
+        .entries
+        .iter()
+        .zip(view.contents)
+        .map(|(entry, contents)| NamedFile {
+            name: entry.name.clone(),
+            contents: Vec::from(contents),
+        })
+        .collect();
+
+    Ok(ParsedPac {
+        meta: view.meta,
+        files,
+    })
+}
+
+/// Parses an archive into owned entries like `parse`, calling `progress` after copying out
+/// each entry and stopping with `PacError::Cancelled` as soon as `cancel` is set.
+pub fn parse_with_progress(
+    input: &[u8],
+    options: &ParseOptions,
+    progress: &dyn Fn(ProgressEvent),
+    cancel: &AtomicBool,
+) -> Result<ParsedPac, PacError> {
+    let view = parse_view_with(input, options)?;
+    let total = view.contents.len();
+
+    let mut files = Vec::with_capacity(total);
+    for (index, (entry, contents)) in view.meta.entries.iter().zip(&view.contents).enumerate() {
+        check_cancelled(cancel)?;
+
+        files.push(NamedFile {
+            name: entry.name.clone(),
+            contents: Vec::from(*contents),
+        });
+        progress(ProgressEvent::Entry {
+            index,
+            total,
+            name: &entry.name,
+        });
+    }
+    progress(ProgressEvent::Finished);
+
+    Ok(ParsedPac {
+        meta: view.meta,
+        files,
+    })
+}
+
+/// Parses an archive without copying any entry data out of `input`.
+pub fn parse_view(input: &[u8]) -> Result<PacView, PacError> {
+    parse_view_with(input, &ParseOptions::default())
+}
+
+/// Reads just the entry table of an archive, without touching the data region.
+///
+/// Offsets and sizes are returned as stored and aren't checked against the length of
+/// `input`.
+pub fn parse_index(input: &[u8]) -> Result<Vec<PacEntryInfo>, PacError> {
+    parse_index_with(input, &ParseOptions::default())
+}
+
+pub fn parse_index_with(
+    input: &[u8],
+    options: &ParseOptions,
+) -> Result<Vec<PacEntryInfo>, PacError> {
+    let header = parse_header(input)?;
+
+    parse_entries(input, header.file_count, header.string_size, options)
+}
+
+pub fn parse_view_with<'a>(
+    input: &'a [u8],
+    options: &ParseOptions,
+) -> Result<PacView<'a>, PacError> {
+    let header = parse_header(input)?;
+
+    let data = input
+        .get(header.data_start as usize..)
+        .ok_or(PacError::DataStartOutOfBounds {
+            data_start: header.data_start,
+            len: input.len() as u64,
+        })?;
+
+    let mut string_size = header.string_size;
+    let mut parsed = parse_entries(input, header.file_count, string_size, options);
+    let mut repaired_from = None;
+
+    // some packers write a string_size that is off by one alignment step, which shifts
+    // every entry after the first
+    if options.repair && !matches!(&parsed, Ok(e) if entries_are_sane(e, data.len())) {
+        let candidates = [
+            string_size.checked_add(ALIGNMENT as u32),
+            string_size.checked_sub(ALIGNMENT as u32),
+        ];
+
+        let repaired = candidates.iter().flatten().find_map(|&candidate| {
+            parse_entries(input, header.file_count, candidate, options)
+                .ok()
+                .filter(|e| entries_are_sane(e, data.len()))
+                .map(|e| (candidate, e))
+        });
+
+        if let Some((candidate, entries)) = repaired {
+            repaired_from = Some(string_size);
+            string_size = candidate;
+            parsed = Ok(entries);
+        }
+    }
+
+    let mut entries = parsed?;
+
+    let mut contents = Vec::with_capacity(entries.len());
+    let mut salvaged_from = None;
+    for (index, entry) in entries.iter().enumerate() {
+        match data_range(entry).and_then(|range| data.get(range)) {
+            Some(entry_data) => contents.push(entry_data),
+            None if options.salvage => {
+                salvaged_from = Some(entries.len());
+                break;
+            }
+            None => {
+                return Err(past_eof(
+                    index,
+                    entry,
+                    header.data_start,
+                    input.len() as u64,
+                ))
+            }
+        }
+    }
+    entries.truncate(contents.len());
+
+    let meta = to_meta(&header, string_size, entries, options);
+
+    Ok(PacView {
+        meta,
+        contents,
+        repaired_from,
+        salvaged_from,
+    })
+}
+
+pub(super) fn parse_entries(
+    input: &[u8],
+    file_count: u32,
+    string_size: u32,
+    options: &ParseOptions,
+) -> Result<Vec<PacEntryInfo>, PacError> {
+    let entry_size = entry_size(string_size, options.entry_flags, options.wide_offsets);
+
+    (0..file_count as usize)
+        .map(|index| {
+            let entry_start = index
+                .checked_mul(entry_size)
+                .and_then(|offset| offset.checked_add(HEADER_SIZE));
+            let entry_end = entry_start.and_then(|start| start.checked_add(entry_size));
+
+            entry_start
+                .zip(entry_end)
+                .and_then(|(start, end)| input.get(start..end))
+                .and_then(|i| parse_entry(i, string_size as usize, options))
+                .ok_or(PacError::FileEntry(index))
+        })
+        .collect()
+}
+
+/// Whether `entries` look like a correctly read table: data laid out in table order and
+/// all of it within the `data_len` bytes of the data region.
+fn entries_are_sane(entries: &[PacEntryInfo], data_len: usize) -> bool {
+    let in_bounds = entries
+        .iter()
+        .all(|e| data_range(e).is_some_and(|range| range.end <= data_len));
+    let monotonic = entries.windows(2).all(|w| w[0].offset <= w[1].offset);
+
+    in_bounds && monotonic
+}
+
+/// Checks that the `total_size` stored in the header matches the actual length of `input`.
+pub fn check_total_size(input: &[u8]) -> Result<(), PacError> {
+    let header = parse_header(input)?;
+
+    if header.total_size as usize != input.len() {
+        return Err(PacError::TotalSize {
+            header: header.total_size,
+            actual: input.len(),
+        });
+    }
+
+    Ok(())
+}
+
+pub(super) fn parse_header(input: &[u8]) -> Result<Header, PacError> {
+    if !input.starts_with(HEADER_MAGIC) {
+        return Err(PacError::InvalidMagic);
+    }
+
+    let header = input.get(..HEADER_SIZE).ok_or(PacError::TruncatedHeader {
+        len: input.len() as u64,
+    })?;
+
+    Ok(Header {
+        data_start: LittleEndian::read_u32(&header[0x4..]),
+        total_size: LittleEndian::read_u32(&header[0x8..]),
+        file_count: LittleEndian::read_u32(&header[0xC..]),
+        unknown: LittleEndian::read_u32(&header[0x10..]),
+        string_size: LittleEndian::read_u32(&header[0x14..]),
+        padding: header[0x18..HEADER_SIZE].try_into().unwrap(),
+    })
+}
+
+/// Turns the entry table read with `options` into the meta of the archive.
+pub(super) fn to_meta(
+    header: &Header,
+    string_size: u32,
+    entries: Vec<PacEntryInfo>,
+    options: &ParseOptions,
+) -> PacMeta {
+    let offset_order = entries.windows(2).any(|w| w[0].offset > w[1].offset);
+    let shares_data_with: Vec<Option<usize>> = entries
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            entries[..index]
+                .iter()
+                .position(|e| e.offset == entry.offset && e.size == entry.size)
+                .filter(|_| entry.size > 0)
+        })
+        .collect();
+
+    let overlapping_data = has_partial_overlap(&entries);
+
+    PacMeta {
+        unknown: header.unknown,
+        string_size,
+        header_padding: Some(header.padding).filter(|p| *p != [0; 8]),
+        data_start: header.data_start,
+        data_align: infer_data_align(
+            header.data_start,
+            HEADER_SIZE
+                + entry_size(string_size, options.entry_flags, options.wide_offsets)
+                    * header.file_count as usize,
+        ),
+        name_terminator: options.name_terminator,
+        entry_flags: options.entry_flags,
+        wide_offsets: options.wide_offsets,
+        entries: entries
+            .into_iter()
+            .zip(shares_data_with)
+            .map(|(entry, shares_data_with)| PacMetaEntry {
+                name: entry.name,
+                raw_name: entry.raw_name,
+                file: None,
+                file_id: entry.file_id,
+                offset: entry.offset,
+                size: entry.size,
+                flags: entry.flags,
+                shares_data_with,
+                hash: None,
+                shard: None,
+                text: None,
+                nested: None,
+            })
+            .collect(),
+        offset_order,
+        split_meta: false,
+        shards: None,
+        overlapping_data,
+        dedup_data: false,
+        cas: None,
+        uncompressed: false,
+        partial: false,
+    }
+}
+
+/// Whether the data of any two entries overlaps without being exactly the same range.
+fn has_partial_overlap(entries: &[PacEntryInfo]) -> bool {
+    let mut ranges: Vec<(u64, u64)> = entries
+        .iter()
+        .filter(|e| e.size > 0)
+        .map(|e| (e.offset, e.offset.saturating_add(e.size)))
+        .collect();
+    ranges.sort_unstable();
+    ranges.dedup();
+
+    ranges.windows(2).any(|w| w[1].0 < w[0].1)
+}
+
+/// The error for `entry` at `index` running past the end of an archive of `len` bytes
+/// whose data region starts at `data_start`.
+pub(super) fn past_eof(index: usize, entry: &PacEntryInfo, data_start: u32, len: u64) -> PacError {
+    PacError::EntryExtendsPastEof {
+        index,
+        offset: (data_start as u64).saturating_add(entry.offset),
+        size: entry.size,
+        len,
+    }
+}
+
+/// The range of the data region `entry` points at, if it's addressable at all.
+pub(super) fn data_range(entry: &PacEntryInfo) -> Option<Range<usize>> {
+    let start = usize::try_from(entry.offset).ok()?;
+    let end = start.checked_add(usize::try_from(entry.size).ok()?)?;
+
+    Some(start..end)
+}
+
+fn parse_entry(input: &[u8], string_size: usize, options: &ParseOptions) -> Option<PacEntryInfo> {
+    let (name, raw_name) = take_str_of_size(
+        input,
+        string_size,
+        options.name_terminator,
+        options.lossy_names,
+    )?;
+    let fields = input
+        .get(string_size..string_size + fields_size(options.entry_flags, options.wide_offsets))?;
+
+    let (offset, size, flags_start) = if options.wide_offsets {
+        (
+            LittleEndian::read_u64(&fields[0x4..]),
+            LittleEndian::read_u64(&fields[0xC..]),
+            0x14,
+        )
+    } else {
+        (
+            LittleEndian::read_u32(&fields[0x4..]) as u64,
+            LittleEndian::read_u32(&fields[0x8..]) as u64,
+            0xC,
+        )
+    };
+
+    Some(PacEntryInfo {
+        name,
+        raw_name,
+        file_id: LittleEndian::read_u32(&fields[0x0..]),
+        offset,
+        size,
+        flags: if options.entry_flags {
+            Some(LittleEndian::read_u32(&fields[flags_start..]))
+        } else {
+            None
+        },
+    })
+}
+
+/// Reads a name out of a fixed-size field, ending at `terminator`.
+///
+/// With `lossy` set, names that aren't valid UTF-8 are decoded with replacement characters
+/// and their original bytes are returned alongside so they can be written back unchanged.
+fn take_str_of_size(
+    input: &[u8],
+    size: usize,
+    terminator: NameTerminator,
+    lossy: bool,
+) -> Option<(String, Option<Vec<u8>>)> {
+    let field = input.get(..size)?;
+    let end = match terminator {
+        NameTerminator::Byte(t) => field.iter().position(|&b| b == t).unwrap_or(size),
+        NameTerminator::FullWidth => size,
+    };
+    let bytes = &field[..end];
+
+    match std::str::from_utf8(bytes) {
+        Ok(name) => Some((name.to_string(), None)),
+        Err(_) if lossy => Some((
+            String::from_utf8_lossy(bytes).into_owned(),
+            Some(bytes.to_vec()),
+        )),
+        Err(_) => None,
+    }
+}
@@ -0,0 +1,110 @@
+use std::convert::TryFrom;
+use std::io::{self, prelude::*, SeekFrom};
+
+use super::parser::{data_range, parse_entries, parse_header, past_eof, to_meta};
+use super::{entry_size, PacError, PacMeta, PacMetaEntry, ParseOptions, HEADER_SIZE};
+
+/// An archive whose entry table has been read from `R`, with entries read on demand by
+/// seeking to their data, so the archive never has to fit in memory.
+pub struct PacReader<R> {
+    reader: R,
+    meta: PacMeta,
+}
+
+/// Reads the header and entry table of the archive in `reader`.
+pub fn parse_reader<R: Read + Seek>(reader: R) -> Result<PacReader<R>, PacError> {
+    parse_reader_with(reader, &ParseOptions::default())
+}
+
+/// Like `parse_reader`, reading the entry table as described by `options`. `repair` and
+/// `salvage` only apply when parsing from a slice.
+pub fn parse_reader_with<R: Read + Seek>(
+    mut reader: R,
+    options: &ParseOptions,
+) -> Result<PacReader<R>, PacError> {
+    let len = reader.seek(SeekFrom::End(0))?;
+
+    let mut table = vec![0; HEADER_SIZE];
+    reader.seek(SeekFrom::Start(0))?;
+    read_exact_or(&mut reader, &mut table, PacError::TruncatedHeader { len })?;
+    let header = parse_header(&table)?;
+
+    if header.data_start as u64 > len {
+        return Err(PacError::DataStartOutOfBounds {
+            data_start: header.data_start,
+            len,
+        });
+    }
+
+    let table_end = entry_size(
+        header.string_size,
+        options.entry_flags,
+        options.wide_offsets,
+    )
+    .checked_mul(header.file_count as usize)
+    .and_then(|size| size.checked_add(HEADER_SIZE))
+    .filter(|&end| end as u64 <= len)
+    .ok_or(PacError::FileEntry(0))?;
+    table.resize(table_end, 0);
+    read_exact_or(
+        &mut reader,
+        &mut table[HEADER_SIZE..],
+        PacError::FileEntry(0),
+    )?;
+
+    let entries = parse_entries(&table, header.file_count, header.string_size, options)?;
+
+    let data_len = usize::try_from(len - header.data_start as u64).unwrap_or(usize::MAX);
+    if let Some((index, entry)) = entries
+        .iter()
+        .enumerate()
+        .find(|(_, e)| !data_range(e).is_some_and(|range| range.end <= data_len))
+    {
+        return Err(past_eof(index, entry, header.data_start, len));
+    }
+
+    let meta = to_meta(&header, header.string_size, entries, options);
+
+    Ok(PacReader { reader, meta })
+}
+
+impl<R: Read + Seek> PacReader<R> {
+    pub fn meta(&self) -> &PacMeta {
+        &self.meta
+    }
+
+    pub fn entries(&self) -> &[PacMetaEntry] {
+        &self.meta.entries
+    }
+
+    /// Reads the contents of the entry at `index` out of the archive.
+    pub fn read_entry(&mut self, index: usize) -> Result<Vec<u8>, PacError> {
+        let entry = self
+            .meta
+            .entries
+            .get(index)
+            .ok_or(PacError::FileEntry(index))?;
+        let size = usize::try_from(entry.size).map_err(|_| PacError::FileEntry(index))?;
+
+        self.reader
+            .seek(SeekFrom::Start(self.meta.data_start as u64 + entry.offset))?;
+
+        let mut contents = vec![0; size];
+        read_exact_or(&mut self.reader, &mut contents, PacError::FileEntry(index))?;
+
+        Ok(contents)
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+/// Fills `buf`, reporting running out of data as `eof` rather than as an I/O error.
+fn read_exact_or<R: Read>(reader: &mut R, buf: &mut [u8], eof: PacError) -> Result<(), PacError> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Err(eof),
+        Err(e) => Err(PacError::Io(e)),
+    }
+}
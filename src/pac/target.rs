@@ -0,0 +1,47 @@
+use std::str::FromStr;
+
+use super::PacMeta;
+
+/// Header values a particular game expects in its archives, so they don't have to be
+/// passed one by one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Target {
+    pub name: &'static str,
+    /// Bytes filling the header after `string_size`
+    pub header_padding: [u8; 8],
+    pub unknown: u32,
+    pub data_align: Option<u32>,
+}
+
+impl Target {
+    /// Every known profile. Only profiles whose values match the headers of real archives
+    /// belong here; `--data-align` covers layouts without one.
+    pub const ALL: &'static [Target] = &[Target {
+        name: "bbcf",
+        header_padding: [0; 8],
+        unknown: 0,
+        data_align: None,
+    }];
+
+    /// Overwrites the header fields of `meta` with the values of this profile.
+    pub fn apply(&self, meta: &mut PacMeta) {
+        meta.header_padding = Some(self.header_padding).filter(|p| *p != [0; 8]);
+        meta.unknown = self.unknown;
+        meta.data_align = self.data_align;
+    }
+}
+
+impl FromStr for Target {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Target::ALL
+            .iter()
+            .find(|t| t.name.eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| {
+                let names: Vec<&str> = Target::ALL.iter().map(|t| t.name).collect();
+                anyhow::anyhow!("Unknown target {}, expected one of {}", s, names.join(", "))
+            })
+    }
+}
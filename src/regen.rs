@@ -0,0 +1,106 @@
+use std::path::Path;
+
+use anyhow::{Context, Result as AResult};
+
+use crate::disk::Disk;
+use crate::{
+    apply_header_overrides, find_meta, warn, write_meta, MetaKind, Run, COMPRESSED_META_FILENAME,
+    META_FILENAME,
+};
+use unpac::pac::{self, PacMeta, PacMetaEntry};
+
+/// Rebuilds a PAC meta.json for `folder` from the files inside it, so a folder whose
+/// meta was lost can still be repacked.
+///
+/// Entries are ordered by name and numbered sequentially, and header fields that can't be
+/// recovered from the files get default values, so the result won't byte-match the
+/// original archive.
+pub fn regen_meta(folder: &Path, opt: &Run) -> AResult<()> {
+    let fs = Disk(opt);
+
+    if find_meta(folder, &fs).is_some() && !opt.overwrite {
+        return Err(anyhow::anyhow!(
+            "{} already has a meta.json, pass --overwrite to replace it",
+            folder.display()
+        ));
+    }
+
+    let mut files = Vec::new();
+    for dir_entry in std::fs::read_dir(folder).context("Reading folder")? {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name();
+
+        if !dir_entry.file_type()?.is_file()
+            || name == META_FILENAME
+            || name == COMPRESSED_META_FILENAME
+        {
+            continue;
+        }
+
+        let name = name
+            .into_string()
+            .map_err(|n| anyhow::anyhow!("{} is not a valid entry name", n.to_string_lossy()))?;
+        let size = dir_entry.metadata()?.len();
+
+        files.push((name, size));
+    }
+    files.sort();
+
+    let mut offset = 0;
+    let entries = files
+        .into_iter()
+        .enumerate()
+        .map(|(file_id, (name, size))| {
+            let entry = PacMetaEntry {
+                name,
+                raw_name: None,
+                file: None,
+                file_id: file_id as u32,
+                offset,
+                size,
+                flags: None,
+                shares_data_with: None,
+                hash: None,
+                shard: None,
+                text: None,
+                nested: None,
+            };
+            offset = pac::align((offset + size) as usize) as u64;
+
+            entry
+        })
+        .collect();
+
+    let mut meta = PacMeta {
+        unknown: 0,
+        string_size: 0,
+        header_padding: None,
+        data_start: 0,
+        data_align: opt.data_align,
+        name_terminator: opt.name_terminator,
+        entry_flags: false,
+        wide_offsets: false,
+        entries,
+        offset_order: false,
+        split_meta: false,
+        shards: None,
+        overlapping_data: false,
+        dedup_data: opt.dedup_data,
+        cas: None,
+        uncompressed: false,
+        partial: false,
+    };
+    meta.string_size = pac::string_size(&meta);
+    apply_header_overrides(&mut meta, &opt.repack_options());
+
+    warn(
+        opt,
+        format!(
+            "Regenerated meta.json for {} from {} files, the repacked PAC may not match the original byte for byte",
+            folder.display(),
+            meta.entries.len()
+        ),
+    )?;
+
+    write_meta(folder, &MetaKind::Pac(meta), opt.compress_meta, &fs)
+}
@@ -0,0 +1,129 @@
+//! A long-running mode driven by newline-delimited JSON commands on stdin, so a frontend
+//! can reuse one process (and its rayon pool) instead of spawning one per file.
+//!
+//! Every command is a single line such as `{"version":1,"op":"list","path":"a.pac"}` and
+//! gets exactly one response line on stdout. Commands run concurrently, so responses may
+//! arrive out of order; an optional `id` is echoed back to match them up.
+
+use std::io::{prelude::*, BufReader, BufWriter};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use anyhow::Result as AResult;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{extract_file, list, open_input, Run};
+use unpac::pac::PacMetaEntry;
+
+/// Bumped whenever commands or responses change incompatibly
+pub const PROTOCOL_VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    version: u32,
+    #[serde(default)]
+    id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    command: Command,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Command {
+    Extract { path: PathBuf },
+    List { path: PathBuf },
+}
+
+#[derive(Debug, Serialize)]
+struct Response {
+    version: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    id: Option<serde_json::Value>,
+    #[serde(flatten)]
+    outcome: Outcome,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+enum Outcome {
+    Ok {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        entries: Option<Vec<PacMetaEntry>>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Answers commands from stdin until it is closed.
+pub fn serve(opt: &Run) -> AResult<()> {
+    let stdout = Mutex::new(BufWriter::new(std::io::stdout()));
+
+    BufReader::new(std::io::stdin())
+        .lines()
+        .par_bridge()
+        .try_for_each(|line| -> AResult<()> {
+            let line = line?;
+            if line.trim().is_empty() {
+                return Ok(());
+            }
+
+            let response = handle_line(&line, opt);
+
+            let mut stdout = stdout.lock().unwrap_or_else(|e| e.into_inner());
+            serde_json::to_writer(&mut *stdout, &response)?;
+            writeln!(stdout)?;
+            stdout.flush()?;
+
+            Ok(())
+        })
+}
+
+fn handle_line(line: &str, opt: &Run) -> Response {
+    let request: Request = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => {
+            return Response {
+                version: PROTOCOL_VERSION,
+                id: None,
+                outcome: Outcome::Error {
+                    message: format!("Invalid command: {}", e),
+                },
+            }
+        }
+    };
+
+    let outcome = if request.version != PROTOCOL_VERSION {
+        Err(anyhow::anyhow!(
+            "Unsupported protocol version {}, expected {}",
+            request.version,
+            PROTOCOL_VERSION
+        ))
+    } else {
+        run_command(request.command, opt)
+    };
+
+    Response {
+        version: PROTOCOL_VERSION,
+        id: request.id,
+        outcome: match outcome {
+            Ok(entries) => Outcome::Ok { entries },
+            Err(e) => Outcome::Error {
+                message: format!("{:#}", e),
+            },
+        },
+    }
+}
+
+fn run_command(command: Command, opt: &Run) -> AResult<Option<Vec<PacMetaEntry>>> {
+    match command {
+        Command::Extract { path } => {
+            let file_buf = open_input(&path)?;
+            extract_file(&path, &file_buf, opt)?;
+
+            Ok(None)
+        }
+        Command::List { path } => list::list_entries(&path, opt).map(Some),
+    }
+}
@@ -0,0 +1,107 @@
+//! Extraction into a number of shard folders balanced by the total size of their entries,
+//! so separate workers can each process one. Every shard gets a `shard.json` fragment with
+//! its entries while meta.json only records how many shards there are.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result as AResult};
+use serde::{Deserialize, Serialize};
+
+use crate::pac::PacMetaEntry;
+use crate::vfs::{FileSink, FileSource};
+
+const FRAGMENT_FILENAME: &str = "shard.json";
+
+#[derive(Serialize, Deserialize)]
+struct FragmentEntry {
+    /// Position of the entry in the entry table
+    index: usize,
+    #[serde(flatten)]
+    entry: PacMetaEntry,
+}
+
+fn shard_folder(folder: &Path, shard: u32) -> PathBuf {
+    folder.join(format!("shard-{}", shard))
+}
+
+/// Where `entry` is extracted to within `folder`, inside its shard folder if it has one.
+pub fn entry_path(folder: &Path, entry: &PacMetaEntry) -> PathBuf {
+    match entry.shard {
+        Some(shard) => shard_folder(folder, shard).join(entry.file_name()),
+        None => folder.join(entry.file_name()),
+    }
+}
+
+/// Spreads `entries` over `shards` shards so each ends up with about the same number of
+/// bytes, by handing out the largest entries first to whichever shard holds the least.
+pub fn assign_shards(entries: &mut [PacMetaEntry], shards: u32) {
+    let mut loads = vec![0u64; shards as usize];
+
+    let mut by_size: Vec<usize> = (0..entries.len()).collect();
+    by_size.sort_by_key(|&index| std::cmp::Reverse(entries[index].size));
+
+    for index in by_size {
+        let (shard, load) = loads
+            .iter_mut()
+            .enumerate()
+            .min_by_key(|(_, load)| **load)
+            .unwrap();
+
+        *load += entries[index].size;
+        entries[index].shard = Some(shard as u32);
+    }
+}
+
+/// Creates the shard folders inside `folder` and writes the fragment of each.
+pub fn write_fragments(
+    entries: &[PacMetaEntry],
+    shards: u32,
+    folder: &Path,
+    fs: &impl FileSink,
+) -> AResult<()> {
+    for shard in 0..shards {
+        let fragment: Vec<FragmentEntry> = entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.shard == Some(shard))
+            .map(|(index, entry)| FragmentEntry {
+                index,
+                entry: entry.clone(),
+            })
+            .collect();
+
+        let shard_folder = shard_folder(folder, shard);
+        fs.create_dir_all(&shard_folder)?;
+
+        let bytes = serde_json::to_vec_pretty(&fragment)?;
+        fs.write(&shard_folder.join(FRAGMENT_FILENAME), &bytes)
+            .with_context(|| format!("Writing fragment of shard {}", shard))?;
+    }
+
+    Ok(())
+}
+
+/// Reads the fragments of all `shards` shards in `folder` back into entries, in their
+/// original table order.
+pub fn read_fragments(
+    folder: &Path,
+    shards: u32,
+    fs: &impl FileSource,
+) -> AResult<Vec<PacMetaEntry>> {
+    let mut fragments = Vec::new();
+
+    for shard in 0..shards {
+        let fragment_path = shard_folder(folder, shard).join(FRAGMENT_FILENAME);
+        let fragment: Vec<FragmentEntry> = serde_json::from_slice(
+            &fs.read(&fragment_path)
+                .with_context(|| format!("Reading fragment of shard {}", shard))?,
+        )
+        .with_context(|| format!("Parsing {}", fragment_path.display()))?;
+
+        fragments.extend(fragment);
+    }
+
+    fragments.sort_by_key(|f| f.index);
+
+    Ok(fragments.into_iter().map(|f| f.entry).collect())
+}
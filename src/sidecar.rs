@@ -0,0 +1,68 @@
+//! Per-entry sidecar files, written next to each extracted PAC entry instead of listing
+//! every entry in one meta.json, so entries can be edited independently without every
+//! change touching the same file.
+
+use std::path::Path;
+
+use anyhow::{Context, Result as AResult};
+use serde::{Deserialize, Serialize};
+
+use crate::pac::PacMetaEntry;
+use crate::vfs::{FileSink, FileSource};
+
+const SIDECAR_SUFFIX: &str = ".meta.json";
+
+#[derive(Serialize, Deserialize)]
+struct Sidecar {
+    /// Position of the entry in the entry table
+    index: usize,
+    #[serde(flatten)]
+    entry: PacMetaEntry,
+}
+
+/// File name of the sidecar of `entry`, `<name>.meta.json`.
+pub fn sidecar_name(entry: &PacMetaEntry) -> String {
+    format!("{}{}", entry.file_name(), SIDECAR_SUFFIX)
+}
+
+/// Writes a `<name>.meta.json` sidecar into `folder` for every entry.
+pub fn write_sidecars(entries: &[PacMetaEntry], folder: &Path, fs: &impl FileSink) -> AResult<()> {
+    for (index, entry) in entries.iter().enumerate() {
+        let sidecar = Sidecar {
+            index,
+            entry: entry.clone(),
+        };
+        let sidecar_path = folder.join(sidecar_name(entry));
+
+        let bytes = serde_json::to_vec_pretty(&sidecar)?;
+        fs.write(&sidecar_path, &bytes)
+            .with_context(|| format!("Writing sidecar for {}", entry.name))?;
+    }
+
+    Ok(())
+}
+
+/// Reads the sidecars in `folder` back into entries, in their original table order.
+pub fn read_sidecars(folder: &Path, fs: &impl FileSource) -> AResult<Vec<PacMetaEntry>> {
+    let mut sidecars = Vec::new();
+
+    for path in fs.list_files(folder).context("Reading folder")? {
+        let is_sidecar = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.ends_with(SIDECAR_SUFFIX));
+
+        if !is_sidecar {
+            continue;
+        }
+
+        let sidecar: Sidecar = serde_json::from_slice(&fs.read(&path)?)
+            .with_context(|| format!("Parsing {}", path.display()))?;
+
+        sidecars.push(sidecar);
+    }
+
+    sidecars.sort_by_key(|s| s.index);
+
+    Ok(sidecars.into_iter().map(|s| s.entry).collect())
+}
@@ -0,0 +1,89 @@
+//! Transcoding of text entries such as Shift-JIS scripts with CRLF endings into UTF-8 with
+//! LF endings for editing, recording their original form so repack can restore it exactly.
+
+use std::str::FromStr;
+
+use anyhow::Result as AResult;
+use encoding_rs::Encoding;
+use glob::Pattern;
+
+use crate::pac::TextForm;
+
+/// A `--text-entries` rule: entries whose name matches `pattern` are text in `encoding`.
+#[derive(Debug, Clone)]
+pub struct TextRule {
+    pattern: Pattern,
+    encoding: &'static Encoding,
+}
+
+impl FromStr for TextRule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> AResult<Self> {
+        let (pattern, label) = s
+            .rsplit_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <glob>=<encoding>, got {}", s))?;
+
+        Ok(TextRule {
+            pattern: Pattern::new(pattern)?,
+            encoding: Encoding::for_label(label.as_bytes())
+                .ok_or_else(|| anyhow::anyhow!("Unknown encoding {}", label))?,
+        })
+    }
+}
+
+/// The UTF-8, LF-only form of the entry `name` if a rule marks it as text, along with how to
+/// get the original back.
+///
+/// Entries that don't decode cleanly, look binary or wouldn't encode back to exactly
+/// `contents` (such as mixed line endings) are left alone.
+pub fn to_editable(name: &str, contents: &[u8], rules: &[TextRule]) -> Option<(Vec<u8>, TextForm)> {
+    let rule = rules.iter().find(|r| r.pattern.matches(name))?;
+
+    if contents.contains(&0) {
+        return None;
+    }
+
+    let text = rule
+        .encoding
+        .decode_without_bom_handling_and_without_replacement(contents)?;
+
+    let form = TextForm {
+        encoding: rule.encoding.name().to_string(),
+        crlf: text.contains("\r\n"),
+    };
+    let editable = if form.crlf {
+        text.replace("\r\n", "\n")
+    } else {
+        text.into_owned()
+    };
+
+    match restore(editable.as_bytes(), &form) {
+        Ok(restored) if restored == contents => Some((editable.into_bytes(), form)),
+        _ => None,
+    }
+}
+
+/// Turns an edited text entry back into its original encoding and line endings.
+pub fn restore(editable: &[u8], form: &TextForm) -> AResult<Vec<u8>> {
+    let encoding = Encoding::for_label(form.encoding.as_bytes())
+        .ok_or_else(|| anyhow::anyhow!("Unknown encoding {}", form.encoding))?;
+
+    let text = std::str::from_utf8(editable)
+        .map_err(|_| anyhow::anyhow!("Text entry is no longer valid UTF-8"))?;
+    let text = if form.crlf {
+        text.replace("\r\n", "\n").replace('\n', "\r\n")
+    } else {
+        text.to_string()
+    };
+
+    let (bytes, _, had_errors) = encoding.encode(&text);
+    if had_errors {
+        return Err(anyhow::anyhow!(
+            "Text entry has characters that can't be written as {}",
+            form.encoding
+        ));
+    }
+
+    Ok(bytes.into_owned())
+}
@@ -0,0 +1,189 @@
+//! An interactive browser for the entries of a PAC, built on the same parser and
+//! extraction helpers as the CLI.
+
+use std::io::prelude::*;
+use std::path::Path;
+
+use anyhow::{Context, Result as AResult};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use crossterm::execute;
+use crossterm::terminal::{
+    disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
+};
+use ratatui::backend::CrosstermBackend;
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Modifier, Style};
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph};
+use ratatui::Terminal;
+
+use crate::{create_output, format_size, read_file, storage_folder, Run};
+use unpac::format::FileKind;
+use unpac::pac::{self, PacMetaEntry, PacView};
+use unpac::plain_pac_bytes;
+
+type CrosstermTerminal = Terminal<CrosstermBackend<std::io::Stdout>>;
+
+/// Browses the PAC at `path` until the user quits, restoring the terminal afterwards even
+/// if browsing failed.
+pub fn browse(path: &Path, opt: &Run) -> AResult<()> {
+    let file_buf = read_file(path)?;
+    let plain = plain_pac_bytes(&file_buf).context("Decompressing PAC")?;
+    let pac = pac::parse_view_with(&plain, &opt.parse_options()).context("Parsing PAC")?;
+
+    enable_raw_mode()?;
+    execute!(std::io::stdout(), EnterAlternateScreen)?;
+
+    let res = Terminal::new(CrosstermBackend::new(std::io::stdout()))
+        .map_err(anyhow::Error::from)
+        .and_then(|mut terminal| run(&mut terminal, path, &pac, opt));
+
+    disable_raw_mode()?;
+    execute!(std::io::stdout(), LeaveAlternateScreen)?;
+
+    res
+}
+
+fn run(terminal: &mut CrosstermTerminal, path: &Path, pac: &PacView, opt: &Run) -> AResult<()> {
+    let storage_folder = storage_folder(path, opt);
+
+    // extracted under the same safe, unique file names as a full extraction
+    let mut entries = pac.meta.entries.clone();
+    pac::assign_file_names(&mut entries);
+
+    let mut state = ListState::default();
+    if !entries.is_empty() {
+        state.select(Some(0));
+    }
+
+    let mut status = String::from("↑/↓ move, e/enter extract, q quit");
+
+    loop {
+        terminal.draw(|frame| {
+            let rows = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Min(1), Constraint::Length(1)])
+                .split(frame.size());
+            let columns = Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .split(rows[0]);
+
+            let items: Vec<ListItem> = entries
+                .iter()
+                .map(|entry| ListItem::new(entry.name.as_str()))
+                .collect();
+            let list = List::new(items)
+                .block(Block::default().borders(Borders::ALL).title(format!(
+                    "{} ({} entries)",
+                    path.display(),
+                    entries.len()
+                )))
+                .highlight_style(Style::default().add_modifier(Modifier::REVERSED));
+            frame.render_stateful_widget(list, columns[0], &mut state);
+
+            let details = match state.selected() {
+                Some(index) => {
+                    let entry = &entries[index];
+                    let kind = match FileKind::sniff(pac.contents[index]) {
+                        Some(kind) => format!("{:?}", kind),
+                        None => String::from("data"),
+                    };
+
+                    format!(
+                        "name:   {}\nid:     {}\noffset: {:#x}\nsize:   {}\ntype:   {}",
+                        entry.name,
+                        entry.file_id,
+                        entry.offset,
+                        format_size(entry.size),
+                        kind
+                    )
+                }
+                None => String::from("No entries"),
+            };
+            let details = Paragraph::new(details)
+                .block(Block::default().borders(Borders::ALL).title("Entry"));
+            frame.render_widget(details, columns[1]);
+
+            frame.render_widget(Paragraph::new(status.as_str()), rows[1]);
+        })?;
+
+        let key = match event::read()? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => key,
+            _ => continue,
+        };
+
+        let selected = state.selected();
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Up => {
+                state.select(selected.map(|i| i.saturating_sub(1)));
+            }
+            KeyCode::Down => {
+                state.select(selected.map(|i| (i + 1).min(entries.len() - 1)));
+            }
+            KeyCode::Char('e') | KeyCode::Enter => {
+                if let Some(index) = selected {
+                    let entry = &entries[index];
+                    status = match extract_entry(&storage_folder, entry, pac.contents[index], opt) {
+                        Ok(()) => format!(
+                            "Extracted {} to {}",
+                            entry.name,
+                            storage_folder.join(entry.file_name()).display()
+                        ),
+                        Err(e) => format!("Error extracting {}: {:#}", entry.name, e),
+                    };
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn extract_entry(
+    storage_folder: &Path,
+    entry: &PacMetaEntry,
+    contents: &[u8],
+    opt: &Run,
+) -> AResult<()> {
+    if !pac::is_safe_file_name(entry.file_name()) {
+        return Err(anyhow::anyhow!(
+            "Entry {} would be written outside of {}",
+            entry.name,
+            storage_folder.display()
+        ));
+    }
+
+    std::fs::create_dir_all(storage_folder)?;
+
+    create_output(&storage_folder.join(entry.file_name()), opt)
+        .and_then(|mut f| f.write_all(contents))
+        .with_context(|| format!("Writing entry {}", entry.name))
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::*;
+
+    #[test]
+    fn keeps_entries_inside_folder() {
+        let root = std::env::temp_dir().join(format!("unpac-tui-{}", std::process::id()));
+        let folder = root.join("a").join("b");
+        let bytes = pac::Pac::builder()
+            .add_file("../../escaped.bin", 0, b"data".to_vec())
+            .build()
+            .unwrap();
+        let mut entries = pac::parse(&bytes).unwrap().meta.entries;
+        pac::assign_file_names(&mut entries);
+
+        let res = extract_entry(&folder, &entries[0], b"data", &Run::from_iter(&["unpac"]));
+        let escaped = root.join("escaped.bin").exists();
+        let written = folder.join(entries[0].file_name()).is_file();
+        std::fs::remove_dir_all(&root).unwrap();
+
+        res.unwrap();
+        assert!(!escaped);
+        assert!(written);
+    }
+}
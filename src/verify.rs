@@ -0,0 +1,137 @@
+//! Round trips of input files through extraction and repack, to check that repacking gives
+//! back exactly the original bytes.
+
+use std::path::Path;
+
+use anyhow::{Context, Result as AResult};
+use unpac::format::FileKind;
+use unpac::vfs::{FileSource, MemoryFs};
+
+use crate::{
+    color, file_kind, handle_hip, handle_hpl, handle_pac, open_input, repack_dir, storage_folder,
+    Run,
+};
+
+/// Extracts and repacks the file at `path` in memory, so nothing is left on disk, and prints
+/// whether the result matches the original. Returns whether it did.
+pub fn verify_file(path: &Path, opt: &Run) -> AResult<bool> {
+    let input = open_input(path).context("Reading file")?;
+    let difference = round_trip(path, &input, opt)?;
+
+    match &difference {
+        None => println!("{} {}", color::added(opt, "PASS"), path.display()),
+        Some(mismatch) => println!(
+            "{} {}: first difference at {:#x} (original {} bytes, repacked {})",
+            color::error(opt, "FAIL"),
+            path.display(),
+            mismatch.offset,
+            input.len(),
+            mismatch.repacked_len
+        ),
+    }
+
+    Ok(difference.is_none())
+}
+
+struct Mismatch {
+    /// Offset of the first byte that differs, or where the shorter file ends
+    offset: usize,
+    repacked_len: usize,
+}
+
+/// Extracts `input` and repacks it again with the options in `opt`, returning where the
+/// repacked file stops matching `input`, if anywhere.
+fn round_trip(path: &Path, input: &[u8], opt: &Run) -> AResult<Option<Mismatch>> {
+    let kind = file_kind(path, input)
+        .ok_or_else(|| anyhow::anyhow!("File either has no extension or is unrecognized"))?;
+
+    let fs = MemoryFs::new();
+    let folder = storage_folder(path, opt);
+
+    match kind {
+        FileKind::Pac => handle_pac(path, input, folder.clone(), opt, &fs),
+        FileKind::Hip => handle_hip(input, folder.clone(), opt, &fs),
+        FileKind::Hpl => handle_hpl(input, folder.clone(), opt, &fs),
+    }
+    .context("Extracting")?;
+
+    repack_dir(&folder, opt, &fs).context("Repacking")?;
+
+    let repacked = fs
+        .read(&folder.with_extension(kind.extension()))
+        .context("Reading repacked file")?;
+
+    Ok(first_difference(input, &repacked).map(|offset| Mismatch {
+        offset,
+        repacked_len: repacked.len(),
+    }))
+}
+
+/// Offset of the first byte `a` and `b` differ at, counting the end of the shorter one as a
+/// difference.
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    a.iter()
+        .zip(b)
+        .position(|(x, y)| x != y)
+        .or_else(|| Some(a.len().min(b.len())).filter(|_| a.len() != b.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::StructOpt;
+
+    use super::*;
+
+    const TWO_ENTRIES: &[u8] = include_bytes!("../fuzz/corpus/parse/two_entries.pac");
+    const TRAILING_BLACK: &[u8] = include_bytes!("../fixtures/trailing_black.hpl");
+
+    fn run() -> Run {
+        Run::from_iter(&["unpac", "--quiet"])
+    }
+
+    #[test]
+    fn finds_first_difference() {
+        assert_eq!(first_difference(b"abc", b"abc"), None);
+        assert_eq!(first_difference(b"abc", b"abd"), Some(2));
+        assert_eq!(first_difference(b"abc", b"ab"), Some(2));
+        assert_eq!(first_difference(b"", b"a"), Some(0));
+    }
+
+    #[test]
+    fn passes_unchanged_round_trip() {
+        let path = Path::new("two_entries.pac");
+
+        assert!(round_trip(path, TWO_ENTRIES, &run()).unwrap().is_none());
+    }
+
+    #[test]
+    fn reports_first_mismatch() {
+        // repack writes the real length as total_size, so a wrong one can't survive
+        let mut input = TWO_ENTRIES.to_vec();
+        input[0x8..0xC].copy_from_slice(&0xA4u32.to_le_bytes());
+        let path = Path::new("two_entries.pac");
+
+        let mismatch = round_trip(path, &input, &run()).unwrap().unwrap();
+        assert_eq!(mismatch.offset, 0x8);
+        assert_eq!(mismatch.repacked_len, 0xA0);
+    }
+
+    #[test]
+    fn passes_unchanged_hpl_round_trip() {
+        let path = Path::new("trailing_black.hpl");
+
+        assert!(round_trip(path, TRAILING_BLACK, &run()).unwrap().is_none());
+    }
+
+    #[test]
+    fn reports_hpl_mismatch() {
+        // repack writes back the header and palette only, so anything after them is lost
+        let mut input = TRAILING_BLACK.to_vec();
+        input.extend_from_slice(&[0xAA; 4]);
+        let path = Path::new("trailing_black.hpl");
+
+        let mismatch = round_trip(path, &input, &run()).unwrap().unwrap();
+        assert_eq!(mismatch.offset, 0x420);
+        assert_eq!(mismatch.repacked_len, 0x420);
+    }
+}
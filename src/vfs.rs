@@ -0,0 +1,167 @@
+//! The filesystem operations of extraction and repack, behind traits so the same code can
+//! run against the real disk or entirely in memory.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Where extracted and repacked files are written to.
+pub trait FileSink: Sync {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()>;
+
+    /// Creates the file at `path`, or replaces its contents if it already exists.
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()>;
+
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Bytes that can still be written to the existing folder `path`, or `None` if there's
+    /// no meaningful limit.
+    fn available_space(&self, path: &Path) -> io::Result<Option<u64>>;
+}
+
+/// Where extracted folders and their metadata are read back from.
+pub trait FileSource: Sync {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+
+    fn is_file(&self, path: &Path) -> bool;
+
+    /// The files directly inside `folder`.
+    fn list_files(&self, folder: &Path) -> io::Result<Vec<PathBuf>>;
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf>;
+}
+
+/// Both ends at once, as needed by code that reads back what it wrote.
+pub trait FileSystem: FileSink + FileSource {}
+
+impl<T: FileSink + FileSource> FileSystem for T {}
+
+/// The real filesystem through `std::fs`, as used by the plain `extract_*` and `repack_dir`
+/// functions.
+pub struct LocalFs;
+
+impl FileSink for LocalFs {
+    fn create_dir_all(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        std::fs::write(path, contents)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        std::fs::remove_file(path)
+    }
+
+    fn available_space(&self, _path: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+impl FileSource for LocalFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        std::fs::read(path)
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        path.is_file()
+    }
+
+    fn list_files(&self, folder: &Path) -> io::Result<Vec<PathBuf>> {
+        list_files(folder)
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        path.canonicalize()
+    }
+}
+
+/// The files directly inside `folder` on disk.
+pub fn list_files(folder: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+
+    for dir_entry in std::fs::read_dir(folder)? {
+        let dir_entry = dir_entry?;
+        if dir_entry.file_type()?.is_file() {
+            files.push(dir_entry.path());
+        }
+    }
+
+    Ok(files)
+}
+
+/// A filesystem that only lives in memory, for running extraction and repack without
+/// touching the disk.
+#[derive(Default)]
+pub struct MemoryFs {
+    files: Mutex<BTreeMap<PathBuf, Vec<u8>>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> MemoryFs {
+        MemoryFs::default()
+    }
+
+    /// A copy of every file written so far, keyed by path.
+    pub fn files(&self) -> BTreeMap<PathBuf, Vec<u8>> {
+        self.lock().clone()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<BTreeMap<PathBuf, Vec<u8>>> {
+        self.files.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl FileSink for MemoryFs {
+    fn create_dir_all(&self, _path: &Path) -> io::Result<()> {
+        // folders only exist implicitly as the parents of files
+        Ok(())
+    }
+
+    fn write(&self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        self.lock().insert(path.to_path_buf(), contents.to_vec());
+        Ok(())
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.lock().remove(path).map(|_| ()).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            )
+        })
+    }
+
+    fn available_space(&self, _path: &Path) -> io::Result<Option<u64>> {
+        Ok(None)
+    }
+}
+
+impl FileSource for MemoryFs {
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        self.lock().get(path).cloned().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            )
+        })
+    }
+
+    fn is_file(&self, path: &Path) -> bool {
+        self.lock().contains_key(path)
+    }
+
+    fn list_files(&self, folder: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(self
+            .lock()
+            .keys()
+            .filter(|path| path.parent() == Some(folder))
+            .cloned()
+            .collect())
+    }
+
+    fn canonicalize(&self, path: &Path) -> io::Result<PathBuf> {
+        Ok(path.to_path_buf())
+    }
+}